@@ -0,0 +1,322 @@
+use rand::{self, Rng};
+
+use std::time::{Duration, Instant};
+
+use error::Result;
+use logging;
+use placement::PlacementStrategy;
+use protocol::{Message, SegmentStatus};
+use scheduler::{Action, Scheduler};
+use transport::Transport;
+use worm::Worm;
+
+/// Number of attempts for operations the segment cannot continue without
+const MAX_ATTEMPTS: usize = 5;
+
+/// How long the segment listens for gossip when it has nothing else to do
+const GOSSIP_WAIT: Duration = Duration::from_secs(5);
+
+/// What the segment should do after a step of the main loop
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Step {
+    Continue,
+    Exit,
+}
+
+/// A running worm segment: the worm state plus what the main loop keeps locally
+#[derive(Debug)]
+pub struct Agent {
+    pub worm: Worm,
+    scheduler: Box<dyn Scheduler>,
+    placement: Box<dyn PlacementStrategy>,
+    vitals: Vitals,
+}
+
+/// What the segment knows about its own process, which never travels with the worm
+#[derive(Debug, Default, Copy, Clone)]
+struct Vitals {
+    started: Option<Instant>,
+    suicide_counter: usize, // Grows while we should not infect, we die when it is high
+}
+
+/// Retry an operation a few times before giving up on it
+fn retry<T, F>(transport: &T, what: &str, mut op: F) -> Result<()>
+where
+    T: Transport,
+    F: FnMut() -> Result<()>,
+{
+    let mut attempt = 1;
+    loop {
+        match op() {
+            Ok(()) => return Ok(()),
+            Err(e) => {
+                warn!("Attempt {} to {} failed: {}", attempt, what, e);
+                if attempt >= MAX_ATTEMPTS {
+                    return Err(e);
+                }
+            }
+        }
+        attempt += 1;
+        transport.sleep(Duration::from_secs(1));
+    }
+}
+
+/// What the segment knows about the run, as reported to Status
+fn status<T: Transport>(worm: &Worm, vitals: Vitals, transport: &T) -> SegmentStatus {
+    let mut observed_hosts: Vec<String> = worm.observation_data.keys().cloned().collect();
+    observed_hosts.sort();
+    SegmentStatus {
+        current_hostname: worm.current_hostname.clone(),
+        initial_hostname: worm.initial_hostname.clone(),
+        current_segments: worm.current_segments.clone(),
+        cur_num_segments: worm.cur_num_segments,
+        max_num_segments: worm.max_num_segments,
+        observed_hosts,
+        suicide_counter: vitals.suicide_counter,
+        uptime_secs: vitals
+            .started
+            .map_or(0, |started| (transport.now() - started).as_secs()),
+    }
+}
+
+/// Handle a message from another segment, answering Status ourselves
+fn handle_message<T: Transport>(
+    worm: &mut Worm,
+    vitals: Vitals,
+    transport: &T,
+    message: Message,
+) -> Option<Message> {
+    match message {
+        Message::Status => {
+            debug!("Got asked for our status");
+            Some(Message::StatusReport(status(worm, vitals, transport)))
+        }
+        message => worm.handle_message(transport, message),
+    }
+}
+
+/// Handle the messages arriving until deadline
+fn listen<T: Transport>(
+    worm: &mut Worm,
+    vitals: Vitals,
+    transport: &T,
+    deadline: Instant,
+) -> Result<()> {
+    transport.listen(deadline, &mut |message| {
+        handle_message(worm, vitals, transport, message)
+    })
+}
+
+impl Agent {
+    /// Create an agent for a worm that has just arrived on this host
+    ///
+    /// Uses the scheduler and placement strategy chosen for the run, seeded at random.
+    pub fn new(worm: Worm) -> Agent {
+        Agent::seeded(worm, rand::thread_rng().gen())
+    }
+
+    /// Create an agent whose random choices are all derived from seed
+    pub fn seeded(worm: Worm, seed: usize) -> Agent {
+        Agent {
+            scheduler: worm.scheduler.build(seed),
+            placement: worm.placement.build(seed),
+            vitals: Vitals::default(),
+            worm,
+        }
+    }
+
+    /// Let scheduler decide what to do next instead of the one chosen for the run
+    pub fn with_scheduler(mut self, scheduler: Box<dyn Scheduler>) -> Agent {
+        self.scheduler = scheduler;
+        self
+    }
+
+    /// Let placement pick the hosts to infect instead of the one chosen for the run
+    pub fn with_placement(mut self, placement: Box<dyn PlacementStrategy>) -> Agent {
+        self.placement = placement;
+        self
+    }
+
+    /// Handle a message from another segment, returning the reply if any
+    pub fn handle_message<T: Transport>(
+        &mut self,
+        transport: &T,
+        message: Message,
+    ) -> Option<Message> {
+        handle_message(&mut self.worm, self.vitals, transport, message)
+    }
+
+    /// What this segment knows about the run
+    pub fn status<T: Transport>(&self, transport: &T) -> SegmentStatus {
+        status(&self.worm, self.vitals, transport)
+    }
+
+    /// Start listening for messages and get data from wormgate if we don't have it
+    ///
+    /// Gives up and sends a suicide note if either is impossible.
+    pub fn start<T: Transport>(&mut self, transport: &T) -> Result<()> {
+        logging::set_context(self.worm.log_context());
+        self.vitals.started = Some(transport.now());
+        if let Err(e) = transport.start_listening() {
+            error!("Unable to listen for messages ({}) - shutting down", e);
+            if let Err(e) = self.worm.send_suicide_note(transport) {
+                warn!("Unable to send suicide note: {}", e);
+            }
+            return Err(e);
+        }
+
+        if self.worm
+            .observation_data
+            .contains_key(&self.worm.current_hostname)
+        {
+            return Ok(());
+        }
+
+        let worm = &mut self.worm;
+        if let Err(e) = retry(transport, "get data from wormgate", || worm.get_data(transport)) {
+            error!("Giving up on wormgate ({}) - shutting down", e);
+            if let Err(e) = worm.send_suicide_note(transport) {
+                warn!("Unable to send suicide note: {}", e);
+            }
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Run a single iteration of the main loop
+    pub fn step<T: Transport>(&mut self, transport: &T) -> Step {
+        let worm = &mut self.worm;
+        let vitals = &mut self.vitals;
+        logging::set_context(worm.log_context());
+
+        // Handle the messages that arrived while we were busy, without waiting for more
+        if let Err(e) = listen(worm, *vitals, transport, transport.now()) {
+            warn!("Unable to handle pending messages: {}", e);
+        }
+        for event in worm.events.drain(..) {
+            self.scheduler.notify(&event);
+        }
+
+        if worm.aborted {
+            info!("Run {} was aborted - exiting", worm.run_id);
+            return Step::Exit;
+        }
+
+        if worm.is_expired(transport) {
+            info!(
+                "Run {} expired after {} hops - returning partial data",
+                worm.run_id, worm.hops
+            );
+            match worm.return_partial_data(transport) {
+                Ok(()) => info!("Returned partial data - will die now"),
+                Err(e) => error!("Unable to return partial data ({}) - will die now", e),
+            }
+            return Step::Exit;
+        }
+
+        /* Have we retrieved all data items */
+        if worm.is_finished() {
+            info!("Finished gathering all data items");
+            let initial = worm.initial_hostname.clone();
+            if worm.current_hostname == worm.initial_hostname {
+                debug!("Finally back home - should return data");
+                match retry(transport, "return data", || worm.return_data(transport)) {
+                    Ok(()) => info!("Returned data - will die now"),
+                    Err(e) => error!("Unable to return data ({}) - will die now", e),
+                }
+                return Step::Exit;
+            } else if worm.has_segment(&initial) && worm.check_segment(transport, &initial) {
+                // The segment there cannot take our state, so hand it our data instead
+                match worm.push_data(transport, &initial) {
+                    Ok(()) => {
+                        info!("Pushed data to the initial segment - will die now");
+                        if let Err(e) = worm.send_suicide_note(transport) {
+                            warn!("Unable to send suicide note: {}", e);
+                        }
+                        return Step::Exit;
+                    }
+                    Err(e) => warn!("Unable to push data to {} ({}) - will try again", initial, e),
+                }
+            } else {
+                debug!("Need to relocate to initial host");
+                // A new segment is only spawned once, so only the state transfer is retried
+                let relocated = worm.upload_to_host(transport, &initial).and_then(|()| {
+                    retry(transport, "relocate to initial host", || {
+                        worm.send_data_to_host(transport, &initial)
+                    })
+                });
+                if let Err(e) = relocated {
+                    // Nobody took over the data, so leave it with the wormgate here
+                    warn!("Unable to relocate to {} ({}) - returning data here", initial, e);
+                    if let Err(e) = worm.return_partial_data(transport) {
+                        error!("Unable to return data ({}) - it is lost", e);
+                    }
+                }
+                return Step::Exit;
+            }
+        }
+
+        if !worm.should_infect() {
+            vitals.suicide_counter += 3;
+            debug!("Suicide counter: {}", vitals.suicide_counter);
+            if vitals.suicide_counter >= 5 {
+                trace!("Worm {:?} should infect {:?}", worm, worm.should_infect());
+                info!("Should not infect - I'll just die and send a message about it");
+                if let Err(e) = worm.send_suicide_note(transport) {
+                    warn!("Unable to send suicide note: {}", e);
+                }
+                return Step::Exit;
+            } else {
+                debug!("Suicide counter too low - listening for gossip - other suicides");
+                if let Err(e) = listen(worm, *vitals, transport, transport.now() + GOSSIP_WAIT) {
+                    warn!("Unable to listen for gossip: {}", e);
+                }
+            }
+        } else {
+            debug!("Reset suicide counter - don't want to die anymore");
+            vitals.suicide_counter = 0;
+        }
+
+        /* If we should infect another host, do it */
+        trace!("Worm data: {:?}", worm);
+        match self.scheduler.next_action(worm, transport.now()) {
+            Action::Infect => {
+                debug!("Infecting another host and gossiping about it");
+                let candidates = worm.candidates(transport.now());
+                match self.placement.choose(worm, &candidates) {
+                    Some(host) => {
+                        self.placement.tried(&host, transport.now());
+                        match worm.infect(transport, &host) {
+                            Ok(()) => info!("Sent myself to {}!", host),
+                            Err(e) => warn!("Unable to infect {}, skipping it: {}", host, e),
+                        }
+                    }
+                    None => debug!("Could not find a free host"),
+                }
+            }
+            Action::Listen => {
+                debug!("Listening for gossip from other hosts");
+                match listen(worm, *vitals, transport, transport.now() + GOSSIP_WAIT) {
+                    Ok(()) => debug!("Gossip hour complete.."),
+                    Err(e) => warn!("Unable to listen for gossip: {}", e),
+                }
+            }
+            Action::Query => {
+                debug!("Want to query for data");
+                match worm.query_missing_data(transport) {
+                    Ok(()) => debug!("Queried data"),
+                    Err(e) => warn!("Unable to query for data: {}", e),
+                }
+            }
+        }
+        Step::Continue
+    }
+
+    /// Start the segment and run the main loop until it exits
+    pub fn run<T: Transport>(&mut self, transport: &T) {
+        if self.start(transport).is_err() {
+            return;
+        }
+        while self.step(transport) == Step::Continue {}
+    }
+}
@@ -0,0 +1,263 @@
+//! Authentication of frames exchanged between segments of a run
+//!
+//! Segments share a cluster key, read from the `POLY_CLUSTER_KEY` environment
+//! variable when the segment starts. Frames carry an HMAC-SHA256 tag computed
+//! with that key, and segments that have a key reject frames without a valid tag,
+//! frames older than MAX_FRAME_AGE_SECS and frames they have seen before. Segments
+//! without a key reject tagged frames instead of taking them unchecked, and warn that
+//! they run unauthenticated, so a host missing the key stands out rather than letting
+//! anything in.
+//!
+//! The `security` section of a config file only applies to the CLI. Segments,
+//! including the ones wormgates launch with their own environment, never see it, so
+//! the wormgates have to be started with the same keys.
+//!
+//! Binaries uploaded to wormgates carry their SHA-256 digest and an Ed25519 signature
+//! of that digest. The signature is made once per approved build with a signing key
+//! that only the cluster admins hold, and is shipped next to the binary. The initial
+//! segment reads it from there and the worm carries it along, since the copies
+//! wormgates launch have no signature file next to them. Wormgates only get the verify
+//! key, from `POLY_VERIFY_KEY`, which cannot sign anything. `poly keygen` makes a pair.
+
+use ed25519_dalek::{Signature, Signer};
+use hmac::{Hmac, Mac};
+use rand::{OsRng, Rng};
+use sha2::{Digest, Sha256};
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use error::{PolyError, Result};
+
+pub use ed25519_dalek::{SigningKey, VerifyingKey};
+
+/// Environment variable holding the shared cluster key
+pub const CLUSTER_KEY_VAR: &str = "POLY_CLUSTER_KEY";
+
+/// Environment variable holding the hex encoded key used to sign approved builds
+pub const SIGNING_KEY_VAR: &str = "POLY_SIGNING_KEY";
+
+/// Environment variable holding the hex encoded key wormgates check signatures with
+pub const VERIFY_KEY_VAR: &str = "POLY_VERIFY_KEY";
+
+/// HTTP header carrying the hex encoded SHA-256 digest of an uploaded binary
+pub const DIGEST_HEADER: &str = "X-Poly-Sha256";
+
+/// HTTP header carrying the hex encoded signature of an uploaded binary
+pub const SIGNATURE_HEADER: &str = "X-Poly-Signature";
+
+/// Length of an authentication tag in bytes
+pub const TAG_LEN: usize = 32;
+
+/// How far the time of an authenticated frame may be from ours before it is refused
+pub const MAX_FRAME_AGE_SECS: u64 = 60;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Read the cluster key from the environment, if one is provisioned
+pub fn cluster_key() -> Option<Vec<u8>> {
+    env::var(CLUSTER_KEY_VAR)
+        .ok()
+        .filter(|key| !key.is_empty())
+        .map(String::into_bytes)
+}
+
+/// Read the build signing key from the environment, if one is provisioned
+pub fn signing_key() -> Result<Option<SigningKey>> {
+    env::var(SIGNING_KEY_VAR)
+        .ok()
+        .filter(|key| !key.is_empty())
+        .map(|key| parse_signing_key(&key))
+        .transpose()
+}
+
+/// Read the key build signatures are checked with from the environment, if one is provisioned
+pub fn verify_key() -> Result<Option<VerifyingKey>> {
+    env::var(VERIFY_KEY_VAR)
+        .ok()
+        .filter(|key| !key.is_empty())
+        .map(|key| parse_verify_key(&key))
+        .transpose()
+}
+
+/// Decode a hex encoded Ed25519 key of 32 bytes
+fn key_bytes(hex: &str) -> Result<[u8; 32]> {
+    let bytes = from_hex(hex.trim())?;
+    <[u8; 32]>::try_from(bytes.as_slice())
+        .map_err(|_| PolyError::Auth(format!("Key is {} bytes, not 32", bytes.len())))
+}
+
+/// Decode a hex encoded signing key
+pub fn parse_signing_key(hex: &str) -> Result<SigningKey> {
+    Ok(SigningKey::from_bytes(&key_bytes(hex)?))
+}
+
+/// Decode a hex encoded verify key
+pub fn parse_verify_key(hex: &str) -> Result<VerifyingKey> {
+    VerifyingKey::from_bytes(&key_bytes(hex)?)
+        .map_err(|_| PolyError::Auth(String::from("Verify key is not a valid Ed25519 key")))
+}
+
+/// Make a new signing key from the randomness of the operating system
+pub fn generate_signing_key() -> Result<SigningKey> {
+    let mut secret = [0; 32];
+    OsRng::new()?.fill_bytes(&mut secret);
+    Ok(SigningKey::from_bytes(&secret))
+}
+
+fn mac(key: &[u8], parts: &[&[u8]]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+    for part in parts {
+        mac.update(part);
+    }
+    mac
+}
+
+/// Compute the authentication tag for the concatenation of parts
+pub fn sign(key: &[u8], parts: &[&[u8]]) -> Vec<u8> {
+    mac(key, parts).finalize().into_bytes().to_vec()
+}
+
+/// Check the authentication tag for the concatenation of parts in constant time
+pub fn verify(key: &[u8], parts: &[&[u8]], tag: &[u8]) -> Result<()> {
+    mac(key, parts)
+        .verify_slice(tag)
+        .map_err(|_| PolyError::Auth(String::from("Invalid authentication tag")))
+}
+
+/// Nonces of the authenticated frames accepted lately, so none is accepted twice
+///
+/// Nonces are forgotten once their frames are too old to be accepted anyway.
+#[derive(Debug, Default)]
+pub struct ReplayGuard {
+    seen: HashMap<u64, u64>,
+}
+
+impl ReplayGuard {
+    pub fn new() -> ReplayGuard {
+        ReplayGuard::default()
+    }
+
+    /// Accept a frame made at sent_at with nonce, unless it is stale or a replay
+    pub fn check(&mut self, sent_at: u64, nonce: u64, now: u64) -> Result<()> {
+        if sent_at.saturating_add(MAX_FRAME_AGE_SECS) < now
+            || sent_at > now.saturating_add(MAX_FRAME_AGE_SECS)
+        {
+            return Err(PolyError::Auth(format!(
+                "Frame made at {} is stale at {}",
+                sent_at, now
+            )));
+        }
+        self.seen
+            .retain(|_, seen_at| seen_at.saturating_add(MAX_FRAME_AGE_SECS) >= now);
+        if self.seen.insert(nonce, sent_at).is_some() {
+            return Err(PolyError::Auth(String::from("Frame was replayed")));
+        }
+        Ok(())
+    }
+}
+
+/// SHA-256 digest of data
+pub fn digest(data: &[u8]) -> Vec<u8> {
+    Sha256::digest(data).to_vec()
+}
+
+/// Encode bytes as lowercase hex
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Decode a hex string into bytes
+pub fn from_hex(hex: &str) -> Result<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return Err(PolyError::Auth(format!("Invalid hex string {:?}", hex)));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&hex[i..i + 2], 16)
+                .map_err(|_| PolyError::Auth(format!("Invalid hex string {:?}", hex)))
+        })
+        .collect()
+}
+
+/// Sign the digest of an approved binary, returning the hex encoded signature
+pub fn sign_binary(signing_key: &SigningKey, binary: &[u8]) -> String {
+    to_hex(&signing_key.sign(&digest(binary)).to_bytes())
+}
+
+/// Check that binary matches the digest and that the digest was signed for verify_key
+pub fn verify_binary(
+    verify_key: &VerifyingKey,
+    binary: &[u8],
+    digest_hex: &str,
+    signature_hex: &str,
+) -> Result<()> {
+    let actual = digest(binary);
+    if from_hex(digest_hex)? != actual {
+        return Err(PolyError::Auth(String::from(
+            "Binary does not match its digest",
+        )));
+    }
+    Signature::from_slice(&from_hex(signature_hex)?)
+        .and_then(|signature| verify_key.verify_strict(&actual, &signature))
+        .map_err(|_| PolyError::Auth(String::from("Binary is not signed by an approved key")))
+}
+
+/// Path of the signature file shipped next to a binary
+pub fn signature_path(binary: &Path) -> PathBuf {
+    let mut path = binary.as_os_str().to_owned();
+    path.push(".sig");
+    PathBuf::from(path)
+}
+
+/// Read the signature shipped next to a binary, if there is one
+pub fn read_signature(binary: &Path) -> Option<String> {
+    let mut signature = String::new();
+    File::open(signature_path(binary))
+        .and_then(|mut f| f.read_to_string(&mut signature))
+        .ok()
+        .map(|_| signature.trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stale_and_replayed_frames() {
+        let mut guard = ReplayGuard::new();
+        assert!(guard.check(1000, 1, 1000).is_ok());
+        assert!(guard.check(1000, 1, 1001).is_err());
+        assert!(guard.check(1000, 2, 1000 + MAX_FRAME_AGE_SECS + 1).is_err());
+        assert!(guard.check(1000 + 2 * MAX_FRAME_AGE_SECS, 3, 1000).is_err());
+    }
+
+    #[test]
+    fn verify_binary_signature() {
+        let binary = b"approved build";
+        let digest = to_hex(&digest(binary));
+        let signing_key = SigningKey::from_bytes(&[1; 32]);
+        let verify_key = signing_key.verifying_key();
+        let other_key = SigningKey::from_bytes(&[2; 32]).verifying_key();
+        let signature = sign_binary(&signing_key, binary);
+        assert!(verify_binary(&verify_key, binary, &digest, &signature).is_ok());
+        assert!(verify_binary(&other_key, binary, &digest, &signature).is_err());
+        assert!(verify_binary(&verify_key, b"other build", &digest, &signature).is_err());
+    }
+
+    #[test]
+    fn parse_keys() {
+        let signing_key = generate_signing_key().unwrap();
+        let hex = to_hex(signing_key.as_bytes());
+        assert_eq!(parse_signing_key(&hex).unwrap(), signing_key);
+        let verify_hex = to_hex(signing_key.verifying_key().as_bytes());
+        assert_eq!(parse_verify_key(&verify_hex).unwrap(), signing_key.verifying_key());
+        assert!(parse_signing_key("abcd").is_err());
+        assert!(parse_verify_key("signing key").is_err());
+    }
+}
@@ -0,0 +1,130 @@
+//! Keeping track of hosts we failed to infect
+//!
+//! Every failed attempt doubles the time before the host is tried again, and after
+//! the maximum number of attempts the segment gives up on the host for the rest of the
+//! run. The retry policy is chosen by the start command and travels with the worm,
+//! along with the failures so far.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Number of failed attempts after which we give up on a host, unless told otherwise
+pub const MAX_ATTEMPTS: usize = 5;
+
+/// Wait after the first failure, doubled for every failure after it
+const INITIAL_BACKOFF_SECS: u64 = 1;
+
+/// Longest we ever wait before trying a host again
+const MAX_BACKOFF_SECS: u64 = 60;
+
+/// How often and how patiently hosts are retried
+#[derive(Deserialize, Serialize, Debug, Copy, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryPolicy {
+    /// Failed attempts after which we give up on a host
+    pub max_attempts: usize,
+    /// Seconds to wait after the first failure, doubled for every failure after it
+    pub initial_backoff_secs: u64,
+    /// Longest we ever wait before trying a host again
+    pub max_backoff_secs: u64,
+}
+
+/// Failed attempts to infect a single host
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Failure {
+    pub attempts: usize,
+    /// When the host may be tried again, only known to the segment that failed on it
+    #[serde(skip)]
+    pub retry_at: Option<Instant>,
+}
+
+/// Failures of every host we were unable to infect
+///
+/// A new segment keeps the attempts made so far and the hosts given up on, but may
+/// try the others again right away.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct FailureTracker {
+    #[serde(default)]
+    policy: RetryPolicy,
+    #[serde(default)]
+    failures: HashMap<String, Failure>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: MAX_ATTEMPTS,
+            initial_backoff_secs: INITIAL_BACKOFF_SECS,
+            max_backoff_secs: MAX_BACKOFF_SECS,
+        }
+    }
+}
+
+impl FailureTracker {
+    pub fn new() -> FailureTracker {
+        FailureTracker::default()
+    }
+
+    /// Track failures with the given policy instead of the default one
+    pub fn with_policy(policy: RetryPolicy) -> FailureTracker {
+        FailureTracker {
+            policy,
+            failures: HashMap::new(),
+        }
+    }
+
+    pub fn policy(&self) -> &RetryPolicy {
+        &self.policy
+    }
+
+    /// Record a failed attempt on host, returning how long until it may be tried again
+    pub fn record_failure(&mut self, host: &str, now: Instant) -> Duration {
+        let failure = self.failures
+            .entry(String::from(host))
+            .or_insert(Failure {
+                attempts: 0,
+                retry_at: None,
+            });
+        failure.attempts += 1;
+        let backoff = cmp::min(
+            Duration::from_secs(self.policy.initial_backoff_secs)
+                .saturating_mul(2u32.saturating_pow(failure.attempts as u32 - 1)),
+            Duration::from_secs(self.policy.max_backoff_secs),
+        );
+        match now.checked_add(backoff) {
+            Some(retry_at) => failure.retry_at = Some(retry_at),
+            // A backoff beyond the end of time means we will never try again
+            None => failure.attempts = cmp::max(failure.attempts, self.policy.max_attempts),
+        }
+        backoff
+    }
+
+    /// Forget the failures of host after it was infected
+    pub fn record_success(&mut self, host: &str) {
+        self.failures.remove(host);
+    }
+
+    /// Determine if we gave up on host
+    pub fn gave_up(&self, host: &str) -> bool {
+        self.failures
+            .get(host)
+            .is_some_and(|failure| failure.attempts >= self.policy.max_attempts)
+    }
+
+    /// Determine if host may be tried now
+    pub fn may_try(&self, host: &str, now: Instant) -> bool {
+        match self.failures.get(host) {
+            Some(failure) => {
+                failure.attempts < self.policy.max_attempts
+                    && failure.retry_at.is_none_or(|retry_at| now >= retry_at)
+            }
+            None => true,
+        }
+    }
+
+    /// Failures recorded so far
+    pub fn failures(&self) -> &HashMap<String, Failure> {
+        &self.failures
+    }
+}
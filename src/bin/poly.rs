@@ -0,0 +1,33 @@
+#[macro_use]
+extern crate poly;
+
+use poly::cli;
+use poly::commands;
+use poly::logging;
+
+use std::env;
+use std::process;
+
+fn main() {
+    if let Err(e) = logging::init_from_env() {
+        println!("Unable to set up logging ({}) - logging to stdout", e);
+    }
+    let args: Vec<String> = env::args().skip(1).collect();
+    let command = match cli::parse(&args) {
+        Ok(command) => command,
+        Err(e) => {
+            println!("{}\n{}", e, cli::USAGE);
+            process::exit(1);
+        }
+    };
+
+    let action = command.action();
+    let result = commands::run(command);
+    if let Err(ref e) = result {
+        error!("Unable to {}: {}", action, e);
+    }
+    logging::flush();
+    if result.is_err() {
+        process::exit(1);
+    }
+}
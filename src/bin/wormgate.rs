@@ -0,0 +1,36 @@
+#[macro_use]
+extern crate poly;
+
+use poly::auth::verify_key;
+use poly::daemon::run_dir;
+use poly::logging;
+use poly::wormgate::FakeWormgate;
+
+use std::env;
+
+fn main() {
+    if let Err(e) = logging::init_from_env() {
+        println!("Unable to set up logging ({}) - logging to stdout", e);
+    }
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.len() < 2 {
+        println!("Usage: wormgate <port> <hostname> [observation]");
+        return;
+    }
+
+    let observation = args.get(2)
+        .cloned()
+        .unwrap_or_else(|| format!("observation from {}", args[1]));
+    let wormgate = FakeWormgate::bind(&format!("0.0.0.0:{}", args[0]), &args[1], &observation)
+        .expect("Unable to bind wormgate");
+    wormgate.require_signed_uploads(verify_key().expect("Invalid verify key"));
+    wormgate.launch_uploads(Some(run_dir().join(format!("wormgate-{}", args[1]))));
+    info!(
+        "Fake wormgate for {} listening on port {}",
+        args[1],
+        wormgate.port().expect("Unable to get wormgate port")
+    );
+    if let Err(e) = wormgate.serve() {
+        error!("Wormgate stopped: {}", e);
+    }
+}
@@ -0,0 +1,322 @@
+//! Command line of the poly binary
+//!
+//! Without a subcommand the binary runs a segment as a daemon, which is what the
+//! wormgate does with an uploaded binary. Flags override the config file, which in
+//! turn overrides the environment variables segments read.
+
+use std::iter::Peekable;
+use std::path::PathBuf;
+use std::slice;
+use std::str::FromStr;
+use std::time::Duration;
+
+use config::{Config, DEFAULT_CONFIG_PATH};
+use daemon::{self, Mode};
+use error::{PolyError, Result};
+use placement::PlacementKind;
+use protocol::{self, Message};
+use scheduler::SchedulerKind;
+use sim::SimConfig;
+use transport::ports;
+
+pub const USAGE: &str = "Usage:
+  poly [segment] [--daemon | --foreground] [--run-dir <dir>] [--run-id <id>]
+       [--wormgate-port <port>]
+  poly start <host> [--max-segments <n>] [--ttl <secs>] [--max-hops <n>]
+       [--scheduler <kind>] [--placement <kind>] [client flags]
+  poly status <host> [client flags]
+  poly abort <host> [client flags]
+  poly simulate [--hosts <n>] [--seed <n>] [--racks <n>] [--max-rounds <n>] [--ttl <secs>]
+       [--max-hops <n>] [--scheduler <kind>] [--placement <kind>] [--run-id <id>]
+  poly sign <binary> [--config <file>]
+  poly keygen
+  poly help
+
+Client flags: [--config <file>] [--run-id <id>] [--wormgate-port <port>] [--timeout <secs>]
+Start reads poly.json unless another config file is named.
+Schedulers: event, random. Placements: in-order, random, lrt, prefix.";
+
+/// What the binary was asked to do
+#[derive(Debug, Clone)]
+pub enum Command {
+    /// Wait for a worm or start command and run the segment
+    Segment(SegmentOptions),
+    /// Send the start command to the segment waiting on a host
+    Start(StartOptions),
+    /// Ask the segments reachable from a host how they are doing
+    Status(Client),
+    /// Abort the run through the segment on a host
+    Abort(Client),
+    /// Run a simulated cluster in memory
+    Simulate(SimConfig),
+    /// Sign an approved build, with the key from the config file if one is named
+    Sign(String, Option<String>),
+    /// Make a signing key for the admins and the verify key for the wormgates
+    Keygen,
+    /// Print the usage
+    Help,
+}
+
+/// How to run a segment
+#[derive(Debug, Clone, PartialEq)]
+pub struct SegmentOptions {
+    pub mode: Mode,
+    pub run_dir: PathBuf,
+    pub run_id: String,
+    pub wormgate_port: Option<u16>,
+}
+
+/// How to reach the segment of a run on a host
+#[derive(Debug, Clone, PartialEq)]
+pub struct Client {
+    pub host: String,
+    pub config: Option<String>,
+    pub run_id: String,
+    pub wormgate_port: Option<u16>,
+    /// How long to wait for the reply of the segment
+    pub timeout: Option<Duration>,
+}
+
+/// The run to start and where to start it
+#[derive(Debug, Clone, PartialEq)]
+pub struct StartOptions {
+    pub client: Client,
+    pub max_segments: Option<usize>,
+    pub ttl_secs: Option<u64>,
+    pub max_hops: Option<usize>,
+    pub scheduler: SchedulerKind,
+    pub placement: PlacementKind,
+}
+
+/// Arguments left to parse
+struct Args<'a> {
+    iter: Peekable<slice::Iter<'a, String>>,
+}
+
+impl<'a> Args<'a> {
+    fn next(&mut self) -> Option<&'a str> {
+        self.iter.next().map(String::as_str)
+    }
+
+    /// Value following flag
+    fn value(&mut self, flag: &str) -> Result<&'a str> {
+        self.next()
+            .ok_or_else(|| PolyError::Config(format!("{} needs a value", flag)))
+    }
+
+    /// Value following flag, parsed
+    fn parse<T: FromStr>(&mut self, flag: &str) -> Result<T> {
+        let value = self.value(flag)?;
+        value
+            .parse()
+            .map_err(|_| PolyError::Config(format!("invalid value {:?} for {}", value, flag)))
+    }
+}
+
+fn unknown(arg: &str) -> PolyError {
+    PolyError::Config(format!("unexpected argument {:?}", arg))
+}
+
+/// Parse the arguments following the program name
+pub fn parse(args: &[String]) -> Result<Command> {
+    let mut args = Args {
+        iter: args.iter().peekable(),
+    };
+    let command = match args.iter.peek() {
+        Some(arg) if arg.starts_with("--") && *arg != "--help" => "segment",
+        Some(_) => args.next().unwrap_or_default(),
+        None => "segment",
+    };
+    match command {
+        "segment" => parse_segment(args),
+        "start" => parse_start(args),
+        "status" => Ok(Command::Status(parse_client(args)?)),
+        "abort" => Ok(Command::Abort(parse_client(args)?)),
+        "simulate" => parse_simulate(args),
+        "sign" => parse_sign(args),
+        "keygen" => match args.next() {
+            Some(arg) => Err(unknown(arg)),
+            None => Ok(Command::Keygen),
+        },
+        "help" | "--help" | "-h" => Ok(Command::Help),
+        _ => Err(PolyError::Config(format!("unknown command {:?}", command))),
+    }
+}
+
+fn parse_segment(mut args: Args) -> Result<Command> {
+    let mut options = SegmentOptions::from_env();
+    while let Some(arg) = args.next() {
+        match arg {
+            "--daemon" => options.mode = Mode::Daemon,
+            "--foreground" => options.mode = Mode::Foreground,
+            "--run-dir" => options.run_dir = PathBuf::from(args.value(arg)?),
+            "--run-id" => options.run_id = String::from(args.value(arg)?),
+            "--wormgate-port" => options.wormgate_port = Some(args.parse(arg)?),
+            _ => return Err(unknown(arg)),
+        }
+    }
+    Ok(Command::Segment(options))
+}
+
+fn parse_start(mut args: Args) -> Result<Command> {
+    let mut options = StartOptions {
+        client: Client::from_env(),
+        max_segments: None,
+        ttl_secs: None,
+        max_hops: None,
+        scheduler: SchedulerKind::default(),
+        placement: PlacementKind::default(),
+    };
+    while let Some(arg) = args.next() {
+        match arg {
+            "--max-segments" => options.max_segments = Some(args.parse(arg)?),
+            "--ttl" => options.ttl_secs = Some(args.parse(arg)?),
+            "--max-hops" => options.max_hops = Some(args.parse(arg)?),
+            "--scheduler" => options.scheduler = args.parse(arg)?,
+            "--placement" => options.placement = args.parse(arg)?,
+            _ => options.client.parse_arg(arg, &mut args)?,
+        }
+    }
+    options.client.check_host()?;
+    Ok(Command::Start(options))
+}
+
+fn parse_client(mut args: Args) -> Result<Client> {
+    let mut client = Client::from_env();
+    while let Some(arg) = args.next() {
+        client.parse_arg(arg, &mut args)?;
+    }
+    client.check_host()?;
+    Ok(client)
+}
+
+fn parse_sign(mut args: Args) -> Result<Command> {
+    let mut binary = None;
+    let mut config = None;
+    while let Some(arg) = args.next() {
+        match arg {
+            "--config" => config = Some(String::from(args.value(arg)?)),
+            _ if binary.is_none() && !arg.starts_with('-') => binary = Some(String::from(arg)),
+            _ => return Err(unknown(arg)),
+        }
+    }
+    match binary {
+        Some(binary) => Ok(Command::Sign(binary, config)),
+        None => Err(PolyError::Config(String::from("sign needs a binary"))),
+    }
+}
+
+fn parse_simulate(mut args: Args) -> Result<Command> {
+    let mut config = SimConfig::default();
+    while let Some(arg) = args.next() {
+        match arg {
+            "--hosts" => config.hosts = args.parse(arg)?,
+            "--seed" => config.seed = args.parse(arg)?,
+            "--racks" => config.racks = args.parse(arg)?,
+            "--max-rounds" => config.max_rounds = args.parse(arg)?,
+            "--ttl" => config.ttl_secs = Some(args.parse(arg)?),
+            "--max-hops" => config.max_hops = Some(args.parse(arg)?),
+            "--scheduler" => config.scheduler = args.parse(arg)?,
+            "--placement" => config.placement = args.parse(arg)?,
+            "--run-id" => config.run_id = String::from(args.value(arg)?),
+            _ => return Err(unknown(arg)),
+        }
+    }
+    Ok(Command::Simulate(config))
+}
+
+impl Command {
+    /// What the command does, for reporting its failure
+    pub fn action(&self) -> &'static str {
+        match *self {
+            Command::Segment(_) => "run segment",
+            Command::Start(_) => "start run",
+            Command::Status(_) => "get status",
+            Command::Abort(_) => "abort run",
+            Command::Simulate(_) => "simulate",
+            Command::Sign(..) => "sign binary",
+            Command::Keygen => "make keys",
+            Command::Help => "print usage",
+        }
+    }
+}
+
+impl SegmentOptions {
+    /// Daemon of the run in POLY_RUN_ID, with the settings from the environment
+    pub fn from_env() -> SegmentOptions {
+        SegmentOptions {
+            mode: Mode::Daemon,
+            run_dir: daemon::run_dir(),
+            run_id: protocol::run_id(),
+            wormgate_port: ports::wormgate_port(),
+        }
+    }
+}
+
+impl Client {
+    /// Client of the run in POLY_RUN_ID, with the settings from the environment
+    pub fn from_env() -> Client {
+        Client {
+            host: String::new(),
+            config: None,
+            run_id: protocol::run_id(),
+            wormgate_port: ports::wormgate_port(),
+            timeout: None,
+        }
+    }
+
+    /// Take a client flag, or the host if we have none yet
+    fn parse_arg(&mut self, arg: &str, args: &mut Args) -> Result<()> {
+        match arg {
+            "--config" => self.config = Some(String::from(args.value(arg)?)),
+            "--run-id" => self.run_id = String::from(args.value(arg)?),
+            "--wormgate-port" => self.wormgate_port = Some(args.parse(arg)?),
+            "--timeout" => self.timeout = Some(Duration::from_secs(args.parse(arg)?)),
+            _ if self.host.is_empty() && !arg.starts_with('-') => self.host = String::from(arg),
+            _ => return Err(unknown(arg)),
+        }
+        Ok(())
+    }
+
+    fn check_host(&self) -> Result<()> {
+        if self.host.is_empty() {
+            return Err(PolyError::Config(String::from("missing host")));
+        }
+        Ok(())
+    }
+
+    /// Load the config file named with --config, if any
+    pub fn config(&self) -> Result<Option<Config>> {
+        self.config.as_ref().map(|path| Config::load(path)).transpose()
+    }
+}
+
+impl StartOptions {
+    /// Load the config file of the run, poly.json unless another one is named
+    pub fn config(&self) -> Result<Config> {
+        Config::load(self.client.config.as_deref().unwrap_or(DEFAULT_CONFIG_PATH))
+    }
+
+    /// Start command for the run, from the config and the flags overriding it
+    pub fn start_command(&self, config: &Config) -> Message {
+        let mut start = config.start_command(&self.client.host);
+        if let Message::Start {
+            ref mut wormgate_port,
+            ref mut max_segments,
+            ref mut ttl_secs,
+            ref mut max_hops,
+            ref mut scheduler,
+            ref mut placement,
+            ..
+        } = start
+        {
+            *wormgate_port = self.client.wormgate_port.unwrap_or(*wormgate_port);
+            *max_segments = self.max_segments.unwrap_or(*max_segments);
+            *ttl_secs = self.ttl_secs.or(*ttl_secs);
+            *max_hops = self.max_hops.or(*max_hops);
+            *scheduler = self.scheduler;
+            *placement = self.placement;
+        }
+        start
+    }
+}
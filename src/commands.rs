@@ -0,0 +1,260 @@
+//! What the poly binary does for each of its commands
+//!
+//! The binary itself only sets up logging, parses the command line and exits with the
+//! outcome of running the command here.
+
+use std::collections::{BTreeSet, HashSet, VecDeque};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use agent::Agent;
+use auth::{self, cluster_key};
+use cli::{self, Client, Command, SegmentOptions, StartOptions};
+use config::Config;
+use daemon::{Mode, RunDir};
+use error::{PolyError, Result};
+use logging::{self, Context};
+use protocol::{AbortAck, Message, SegmentStatus};
+use sim::simulate;
+use transport::{listen_for_worm, local_hostname, TcpTransport, Transport, ABORT_WAIT};
+use transport::tcp::READ_TIMEOUT;
+use transport::ports::port_range;
+
+/// Run command, printing what it has to say to stdout
+pub fn run(command: Command) -> Result<()> {
+    match command {
+        Command::Segment(ref options) => segment(options),
+        Command::Start(ref options) => start(options),
+        Command::Status(ref client) => status(client),
+        Command::Abort(ref client) => abort(client),
+        Command::Simulate(config) => {
+            println!("{}", simulate(config));
+            Ok(())
+        }
+        Command::Sign(ref binary, ref config) => sign(binary, config.as_ref()),
+        Command::Keygen => keygen(),
+        Command::Help => {
+            println!("{}", cli::USAGE);
+            Ok(())
+        }
+    }
+}
+
+/// Transport for talking to the segments of the run of client, flags before config
+fn client_transport(client: &Client, config: Option<&Config>) -> Result<TcpTransport> {
+    let wormgate_port = client
+        .wormgate_port
+        .or_else(|| config.map(|config| config.wormgate_port_of(&client.host)));
+    let timeout = client
+        .timeout
+        .or_else(|| config.map(Config::request_timeout))
+        .unwrap_or(READ_TIMEOUT);
+    Ok(TcpTransport::new(&local_hostname()?)
+        .with_run_id(&client.run_id)
+        .with_port_range(port_range()?)
+        .with_wormgate_port(wormgate_port)
+        .with_wormgate_ports(config.map(Config::wormgate_ports).unwrap_or_default())
+        .with_cluster_key(config.map_or_else(cluster_key, Config::cluster_key))
+        .with_timeout(timeout))
+}
+
+/// Send the start command to the segment of our run waiting on host
+fn start(options: &StartOptions) -> Result<()> {
+    let config = options.config()?;
+    let start = options.start_command(&config);
+    let transport = client_transport(&options.client, Some(&config))?;
+    debug!("Sending {:?} to {}", start, options.client.host);
+    transport.send_start(&options.client.host, &start)?;
+    println!("Started run {}", options.client.run_id);
+    Ok(())
+}
+
+/// Ask the segment on host for its status, and every segment it knows of for theirs
+///
+/// Prints the view of each segment that replied, followed by the run as a whole.
+fn status(client: &Client) -> Result<()> {
+    let config = client.config()?;
+    let mut queue = VecDeque::new();
+    queue.push_back(client.host.clone());
+    let mut seen: HashSet<String> = queue.iter().cloned().collect();
+    let mut reports = Vec::new();
+    let mut unreachable = Vec::new();
+
+    while let Some(host) = queue.pop_front() {
+        let host_client = Client {
+            host: host.clone(),
+            ..client.clone()
+        };
+        let transport = client_transport(&host_client, config.as_ref())?;
+        match transport.request(&host, &Message::Status) {
+            Ok(Message::StatusReport(report)) => {
+                for segment in &report.current_segments {
+                    if seen.insert(segment.hostname.clone()) {
+                        queue.push_back(segment.hostname.clone());
+                    }
+                }
+                reports.push(report);
+            }
+            Ok(reply) => {
+                warn!("Unexpected reply from {}: {:?}", host, reply);
+                unreachable.push(host);
+            }
+            Err(ref e) if reports.is_empty() && e.is_connection_refused() => {
+                println!("No segment of run {} on {}", client.run_id, host);
+                return Ok(());
+            }
+            Err(e) if reports.is_empty() => return Err(e),
+            Err(e) => {
+                debug!("Unable to get status of {}: {}", host, e);
+                unreachable.push(host);
+            }
+        }
+    }
+
+    for report in &reports {
+        print_status(report);
+    }
+    let observed: BTreeSet<&String> = reports
+        .iter()
+        .flat_map(|report| &report.observed_hosts)
+        .collect();
+    println!(
+        "Run {}: {} segments replied, observation data from {} hosts",
+        client.run_id,
+        reports.len(),
+        observed.len()
+    );
+    for host in unreachable {
+        println!("Unable to reach segment on {}", host);
+    }
+    Ok(())
+}
+
+fn print_status(report: &SegmentStatus) {
+    let role = if report.current_hostname == report.initial_hostname {
+        "initial"
+    } else {
+        "child"
+    };
+    println!(
+        "{} ({}, up {}s): {}/{} segments, suicide counter {}",
+        report.current_hostname,
+        role,
+        report.uptime_secs,
+        report.cur_num_segments,
+        report.max_num_segments,
+        report.suicide_counter
+    );
+    for segment in &report.current_segments {
+        println!("  {:?} {}", segment.relationship, segment.hostname);
+    }
+    println!("  Observed: {}", report.observed_hosts.join(", "));
+}
+
+/// Abort the run through the segment on host and report which segments acknowledged it
+///
+/// Waits ABORT_WAIT for the acknowledgement unless a timeout is given.
+fn abort(client: &Client) -> Result<()> {
+    let wait = client.timeout.unwrap_or(ABORT_WAIT);
+    let transport = client_transport(client, client.config()?.as_ref())?;
+    let msg = Message::Abort {
+        run_id: client.run_id.clone(),
+        seen: Vec::new(),
+        wait_ms: wait.as_millis() as u64,
+    };
+    match transport.request_within(&client.host, &msg, wait)? {
+        Message::AbortAck(ack) => {
+            print_ack(&ack, 0);
+            println!("{} segments acknowledged the abort", ack.hostnames().len());
+            for host in ack.unconfirmed() {
+                println!("Passed the abort on to {} but it did not acknowledge in time", host);
+            }
+            for host in ack.unreachable() {
+                println!("Unable to reach segment on {}", host);
+            }
+            Ok(())
+        }
+        reply => Err(PolyError::Protocol(format!(
+            "Unexpected reply from {}: {:?}",
+            client.host, reply
+        ))),
+    }
+}
+
+fn print_ack(ack: &AbortAck, depth: usize) {
+    println!("{}{}", "  ".repeat(depth), ack.hostname);
+    for child in &ack.children {
+        print_ack(child, depth + 1);
+    }
+}
+
+/// Sign an approved build, writing the signature next to the binary
+fn sign(binary: &str, config: Option<&String>) -> Result<()> {
+    let key = match config {
+        Some(path) => Config::load(path)?.signing_key()?,
+        None => auth::signing_key()?,
+    };
+    let key = key.ok_or_else(|| {
+        PolyError::Auth(format!("{} is not set", auth::SIGNING_KEY_VAR))
+    })?;
+    let mut buf = Vec::new();
+    let _n = File::open(binary)?.read_to_end(&mut buf)?;
+    let path = auth::signature_path(Path::new(binary));
+    writeln!(File::create(&path)?, "{}", auth::sign_binary(&key, &buf))?;
+    println!("Wrote signature to {:?}", path);
+    Ok(())
+}
+
+/// Make a new signing key and print it along with its verify key
+fn keygen() -> Result<()> {
+    let key = auth::generate_signing_key()?;
+    println!("{}={}", auth::SIGNING_KEY_VAR, auth::to_hex(key.as_bytes()));
+    println!("{}={}", auth::VERIFY_KEY_VAR, auth::to_hex(key.verifying_key().as_bytes()));
+    println!("Keep the signing key with the admins, the wormgates only need the verify key");
+    Ok(())
+}
+
+/// Wait for a worm or start command and run the segment until it exits
+fn segment(options: &SegmentOptions) -> Result<()> {
+    /* Segments started by the wormgate get no arguments and run as daemons */
+    let _pidfile = match options.mode {
+        Mode::Daemon => match RunDir::new(&options.run_dir, &options.run_id).daemonize()? {
+            Some(pidfile) => {
+                info!("Running as daemon, pid written to {:?}", pidfile.path());
+                Some(pidfile)
+            }
+            None => return Ok(()),
+        },
+        Mode::Foreground => None,
+    };
+
+    /* Listen for worm or initial message */
+    let hostname = local_hostname()?;
+    logging::set_context(Context {
+        run_id: options.run_id.clone(),
+        hostname: hostname.clone(),
+        role: String::from("waiting"),
+        wormgate_port: options.wormgate_port,
+    });
+    info!("Listening for a worm!");
+    let key = cluster_key();
+    if key.is_none() {
+        warn!(
+            "{} is not set - frames are not authenticated and authenticated ones are refused",
+            auth::CLUSTER_KEY_VAR
+        );
+    }
+    let transport = TcpTransport::new(&hostname)
+        .with_run_id(&options.run_id)
+        .with_port_range(port_range()?)
+        .with_wormgate_port(options.wormgate_port)
+        .with_cluster_key(key);
+    let worm = listen_for_worm(&transport)?;
+    debug!("Worm is: {:?}", worm);
+    let transport = transport.with_wormgate_ports(worm.wormgate_ports.clone());
+
+    Agent::new(worm).run(&transport);
+    info!("Goodbye from me... :)");
+    Ok(())
+}
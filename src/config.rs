@@ -0,0 +1,562 @@
+//! Settings of a gathering run, read from a JSON config file
+//!
+//! Only the wormgate port and the hosts are required, everything else falls back to
+//! the defaults segments use without a config:
+//!
+//! ```json
+//! {
+//!     "wormgate_port": 8181,
+//!     "hosts": ["compute-1-1", {"hostname": "compute-1-2", "port": 8182}],
+//!     "max_segments": 10,
+//!     "timeouts": {"ttl_secs": 3600, "max_hops": 256, "request_secs": 5},
+//!     "gossip_fanout": 5,
+//!     "retry": {"max_attempts": 5, "initial_backoff_secs": 1, "max_backoff_secs": 60},
+//!     "security": {"cluster_key": "secret", "signing_key": "<64 hex digits>"}
+//! }
+//! ```
+//!
+//! Errors found while loading name the line of the offending setting.
+
+use serde_json;
+
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::Read;
+use std::iter::Peekable;
+use std::str::Chars;
+use std::time::Duration;
+
+use auth::{self, SigningKey};
+use backoff::RetryPolicy;
+use error::{PolyError, Result};
+use placement::PlacementKind;
+use protocol::Message;
+use scheduler::SchedulerKind;
+use transport::{DEFAULT_GOSSIP_FANOUT, DEFAULT_MAX_HOPS, DEFAULT_TTL_SECS};
+use transport::tcp::READ_TIMEOUT;
+
+/// Config file the CLI reads when none is named
+pub const DEFAULT_CONFIG_PATH: &str = "poly.json";
+
+/// Longest any duration in a config may be, which keeps deadlines far from overflowing
+pub const MAX_DURATION_SECS: u64 = 365 * 24 * 60 * 60;
+
+/// Settings of a gathering run
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Port of the wormgates, unless a host names its own
+    pub wormgate_port: u16,
+    pub hosts: Vec<HostEntry>,
+    /// Defaults to the number of hosts
+    #[serde(default)]
+    pub max_segments: Option<usize>,
+    #[serde(default)]
+    pub timeouts: Timeouts,
+    #[serde(default = "default_gossip_fanout")]
+    pub gossip_fanout: usize,
+    #[serde(default)]
+    pub retry: RetryPolicy,
+    #[serde(default)]
+    pub security: Security,
+}
+
+/// A host of the run, either just its name or its name with the port of its wormgate
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum HostEntry {
+    Name(String),
+    Host(HostConfig),
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct HostConfig {
+    pub hostname: String,
+    /// Port of the wormgate on this host
+    #[serde(default)]
+    pub port: Option<u16>,
+}
+
+/// How long a run and the requests of the CLI may take
+#[derive(Deserialize, Debug, Copy, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Timeouts {
+    /// Seconds after which segments give up on the run
+    pub ttl_secs: u64,
+    /// State transfers after which a worm gives up on the run
+    pub max_hops: usize,
+    /// Seconds the CLI waits for the reply of a segment
+    pub request_secs: u64,
+}
+
+/// Keys the CLI uses instead of the ones in the environment
+///
+/// Segments always take their keys from the environment, see the auth module.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Security {
+    #[serde(default)]
+    pub cluster_key: Option<String>,
+    #[serde(default)]
+    pub signing_key: Option<String>,
+}
+
+fn default_gossip_fanout() -> usize {
+    DEFAULT_GOSSIP_FANOUT
+}
+
+impl Default for Timeouts {
+    fn default() -> Timeouts {
+        Timeouts {
+            ttl_secs: DEFAULT_TTL_SECS,
+            max_hops: DEFAULT_MAX_HOPS,
+            request_secs: READ_TIMEOUT.as_secs(),
+        }
+    }
+}
+
+impl HostEntry {
+    pub fn hostname(&self) -> &str {
+        match *self {
+            HostEntry::Name(ref hostname) => hostname,
+            HostEntry::Host(ref host) => &host.hostname,
+        }
+    }
+
+    pub fn port(&self) -> Option<u16> {
+        match *self {
+            HostEntry::Name(_) => None,
+            HostEntry::Host(ref host) => host.port,
+        }
+    }
+}
+
+/// Lines of the settings in a config file, for pointing at the offending one
+///
+/// Settings are named by their path, such as `timeouts.ttl_secs` or `hosts.2`.
+struct Source<'a> {
+    path: &'a str,
+    lines: HashMap<String, usize>,
+}
+
+/// Walks the text of a valid JSON document, noting the line every value starts on
+struct Scanner<'a> {
+    chars: Peekable<Chars<'a>>,
+    line: usize,
+    lines: HashMap<String, usize>,
+}
+
+impl<'a> Source<'a> {
+    fn new(path: &'a str, text: &'a str) -> Source<'a> {
+        let mut scanner = Scanner {
+            chars: text.chars().peekable(),
+            line: 1,
+            lines: HashMap::new(),
+        };
+        scanner.value("");
+        Source {
+            path,
+            lines: scanner.lines,
+        }
+    }
+
+    /// Error on the line of the setting at path, or of the closest setting containing it
+    fn error(&self, path: &str, msg: String) -> PolyError {
+        let mut path = path;
+        let line = loop {
+            if let Some(&line) = self.lines.get(path) {
+                break line;
+            }
+            match path.rfind('.') {
+                Some(index) => path = &path[..index],
+                None => break 1,
+            }
+        };
+        PolyError::ConfigFile(String::from(self.path), line, msg)
+    }
+
+    /// Error on the line of the setting at path, naming the setting in msg
+    fn key_error(&self, path: &str, msg: &str) -> PolyError {
+        let key = path.rsplit('.').next().unwrap_or(path);
+        self.error(path, format!("{} {}", key, msg))
+    }
+}
+
+impl<'a> Scanner<'a> {
+    fn skip_whitespace(&mut self) {
+        while let Some(&c) = self.chars.peek() {
+            if !c.is_whitespace() {
+                break;
+            }
+            if c == '\n' {
+                self.line += 1;
+            }
+            self.chars.next();
+        }
+    }
+
+    /// Read the string starting at the next character
+    fn string(&mut self) -> String {
+        let mut s = String::new();
+        self.chars.next();
+        while let Some(c) = self.chars.next() {
+            match c {
+                '"' => break,
+                '\\' => s.extend(self.chars.next()),
+                c => s.push(c),
+            }
+        }
+        s
+    }
+
+    /// Read the value at path, noting the lines of everything inside it
+    fn value(&mut self, path: &str) {
+        self.skip_whitespace();
+        match self.chars.peek().cloned() {
+            Some('{') => {
+                self.chars.next();
+                loop {
+                    self.skip_whitespace();
+                    match self.chars.peek().cloned() {
+                        Some('"') => {
+                            let line = self.line;
+                            let key = self.string();
+                            let child = join(path, &key);
+                            self.lines.insert(child.clone(), line);
+                            self.skip_whitespace();
+                            self.chars.next();
+                            self.value(&child);
+                        }
+                        Some('}') | None => break,
+                        Some(_) => {
+                            self.chars.next();
+                        }
+                    }
+                }
+                self.chars.next();
+            }
+            Some('[') => {
+                self.chars.next();
+                let mut index = 0;
+                loop {
+                    self.skip_whitespace();
+                    match self.chars.peek().cloned() {
+                        Some(']') | None => break,
+                        Some(',') => {
+                            self.chars.next();
+                            index += 1;
+                        }
+                        Some(_) => {
+                            let child = join(path, &index.to_string());
+                            self.lines.insert(child.clone(), self.line);
+                            self.value(&child);
+                        }
+                    }
+                }
+                self.chars.next();
+            }
+            Some('"') => {
+                self.string();
+            }
+            _ => while let Some(&c) = self.chars.peek() {
+                if c == ',' || c == '}' || c == ']' || c.is_whitespace() {
+                    break;
+                }
+                self.chars.next();
+            },
+        }
+    }
+}
+
+/// Path of key inside the setting at path
+fn join(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        String::from(key)
+    } else {
+        format!("{}.{}", path, key)
+    }
+}
+
+impl Config {
+    /// Read and validate the config file at path
+    pub fn load(path: &str) -> Result<Config> {
+        let mut text = String::new();
+        let _n = File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut text))
+            .map_err(|e| PolyError::Config(format!("{}: {}", path, e)))?;
+        Config::parse(path, &text)
+    }
+
+    /// Parse and validate a config, naming it path in errors
+    pub fn parse(path: &str, text: &str) -> Result<Config> {
+        let config: Config = serde_json::from_str(text).map_err(|e| {
+            PolyError::ConfigFile(String::from(path), e.line(), e.to_string())
+        })?;
+        config.validate(&Source::new(path, text))?;
+        Ok(config)
+    }
+
+    fn validate(&self, source: &Source) -> Result<()> {
+        if self.wormgate_port == 0 {
+            return Err(source.key_error("wormgate_port", "must not be 0"));
+        }
+        if self.hosts.is_empty() {
+            return Err(source.key_error("hosts", "must name at least one host"));
+        }
+
+        let mut seen = HashSet::new();
+        for (index, host) in self.hosts.iter().enumerate() {
+            let hostname = host.hostname();
+            let path = format!("hosts.{}", index);
+            if hostname.is_empty() || hostname.contains(char::is_whitespace) {
+                return Err(source.error(&path, format!("invalid hostname {:?}", hostname)));
+            }
+            if !seen.insert(hostname) {
+                return Err(source.error(&path, format!("{} is listed twice", hostname)));
+            }
+            if host.port() == Some(0) {
+                let path = format!("{}.port", path);
+                return Err(source.error(&path, format!("port of {} must not be 0", hostname)));
+            }
+        }
+
+        if self.max_segments == Some(0) {
+            return Err(source.key_error("max_segments", "must be at least 1"));
+        }
+        if self.gossip_fanout == 0 {
+            return Err(source.key_error("gossip_fanout", "must be at least 1"));
+        }
+        if self.timeouts.ttl_secs == 0 {
+            return Err(source.key_error("timeouts.ttl_secs", "must be at least 1"));
+        }
+        if self.timeouts.max_hops == 0 {
+            return Err(source.key_error("timeouts.max_hops", "must be at least 1"));
+        }
+        if self.timeouts.request_secs == 0 {
+            return Err(source.key_error("timeouts.request_secs", "must be at least 1"));
+        }
+        if self.retry.max_attempts == 0 {
+            return Err(source.key_error("retry.max_attempts", "must be at least 1"));
+        }
+        if self.retry.initial_backoff_secs > self.retry.max_backoff_secs {
+            return Err(source.key_error(
+                "retry.initial_backoff_secs",
+                "must not be above max_backoff_secs",
+            ));
+        }
+        let durations = [
+            ("timeouts.ttl_secs", self.timeouts.ttl_secs),
+            ("timeouts.request_secs", self.timeouts.request_secs),
+            ("retry.max_backoff_secs", self.retry.max_backoff_secs),
+        ];
+        for &(path, secs) in &durations {
+            if secs > MAX_DURATION_SECS {
+                let msg = format!("must not be above {} (a year)", MAX_DURATION_SECS);
+                return Err(source.key_error(path, &msg));
+            }
+        }
+        if self.security.cluster_key.as_ref().is_some_and(String::is_empty) {
+            return Err(source.key_error("security.cluster_key", "must not be empty"));
+        }
+        if let Some(ref key) = self.security.signing_key {
+            if let Err(e) = auth::parse_signing_key(key) {
+                return Err(source.key_error("security.signing_key", &e.to_string()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Hostnames of the run, starting with host
+    pub fn hostnames(&self, host: &str) -> Vec<String> {
+        let mut hostnames = vec![String::from(host)];
+        hostnames.extend(
+            self.hosts
+                .iter()
+                .map(HostEntry::hostname)
+                .filter(|h| *h != host)
+                .map(String::from),
+        );
+        hostnames
+    }
+
+    /// Create the start command for a run initiated from host
+    pub fn start_command(&self, host: &str) -> Message {
+        let hostnames = self.hostnames(host);
+        Message::Start {
+            wormgate_port: self.wormgate_port,
+            max_segments: self.max_segments.unwrap_or(hostnames.len()),
+            allowed_hosts: hostnames.clone(),
+            hosts: hostnames,
+            ttl_secs: Some(self.timeouts.ttl_secs),
+            max_hops: Some(self.timeouts.max_hops),
+            scheduler: SchedulerKind::default(),
+            placement: PlacementKind::default(),
+            wormgate_ports: self.wormgate_ports(),
+            gossip_fanout: Some(self.gossip_fanout),
+            retry: self.retry,
+        }
+    }
+
+    /// Hosts with their wormgate on a port of their own
+    pub fn wormgate_ports(&self) -> HashMap<String, u16> {
+        self.hosts
+            .iter()
+            .filter_map(|h| h.port().map(|port| (String::from(h.hostname()), port)))
+            .collect()
+    }
+
+    /// Port of the wormgate on host
+    pub fn wormgate_port_of(&self, host: &str) -> u16 {
+        self.hosts
+            .iter()
+            .find(|h| h.hostname() == host)
+            .and_then(HostEntry::port)
+            .unwrap_or(self.wormgate_port)
+    }
+
+    /// Cluster key from the config, or else from the environment
+    pub fn cluster_key(&self) -> Option<Vec<u8>> {
+        self.security
+            .cluster_key
+            .clone()
+            .map(String::into_bytes)
+            .or_else(auth::cluster_key)
+    }
+
+    /// Build signing key from the config, or else from the environment
+    pub fn signing_key(&self) -> Result<Option<SigningKey>> {
+        match self.security.signing_key {
+            Some(ref key) => auth::parse_signing_key(key).map(Some),
+            None => auth::signing_key(),
+        }
+    }
+
+    /// How long the CLI waits for the reply of a segment
+    pub fn request_timeout(&self) -> Duration {
+        Duration::from_secs(self.timeouts.request_secs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Line and message of the error loading text
+    fn error(text: &str) -> (usize, String) {
+        match Config::parse("poly.json", text) {
+            Err(PolyError::ConfigFile(ref path, line, ref msg)) if path == "poly.json" => {
+                (line, msg.clone())
+            }
+            result => panic!("Parsed {:?}", result),
+        }
+    }
+
+    #[test]
+    fn valid_config() {
+        let config = Config::parse(
+            "poly.json",
+            r#"{
+                "wormgate_port": 8181,
+                "hosts": ["a", {"hostname": "b", "port": 8182}],
+                "timeouts": {"ttl_secs": 60}
+            }"#,
+        ).unwrap();
+        assert_eq!(config.hostnames("b"), vec!["b", "a"]);
+        assert_eq!(config.wormgate_port_of("a"), 8181);
+        assert_eq!(config.wormgate_port_of("b"), 8182);
+        assert_eq!(config.timeouts.ttl_secs, 60);
+        assert_eq!(config.timeouts.max_hops, DEFAULT_MAX_HOPS);
+    }
+
+    #[test]
+    fn nested_key() {
+        let (line, msg) = error(
+            r#"{
+                "wormgate_port": 8181,
+                "hosts": ["a"],
+                "timeouts": {
+                    "max_hops": 3,
+                    "ttl_secs": 0
+                }
+            }"#,
+        );
+        assert_eq!(line, 6);
+        assert_eq!(msg, "ttl_secs must be at least 1");
+    }
+
+    #[test]
+    fn duration_too_long() {
+        let (line, msg) = error(
+            r#"{
+                "wormgate_port": 8181,
+                "hosts": ["a"],
+                "retry": {"max_backoff_secs": 100000000}
+            }"#,
+        );
+        assert_eq!(line, 4);
+        assert!(msg.starts_with("max_backoff_secs must not be above"), "{}", msg);
+    }
+
+    #[test]
+    fn array_element() {
+        let (line, msg) = error(
+            r#"{
+                "wormgate_port": 8181,
+                "hosts": [
+                    "a",
+                    "b",
+                    "c d"
+                ]
+            }"#,
+        );
+        assert_eq!(line, 6);
+        assert_eq!(msg, "invalid hostname \"c d\"");
+    }
+
+    #[test]
+    fn duplicate_host() {
+        let (line, msg) = error(
+            r#"{
+                "wormgate_port": 8181,
+                "hosts": [
+                    "a",
+                    {"hostname": "b", "port": 8182},
+                    {"hostname": "a", "port": 8183}
+                ]
+            }"#,
+        );
+        assert_eq!(line, 6);
+        assert_eq!(msg, "a is listed twice");
+    }
+
+    #[test]
+    fn host_port_zero() {
+        let (line, msg) = error(
+            r#"{
+                "wormgate_port": 8181,
+                "hosts": [
+                    "a",
+                    {
+                        "hostname": "b",
+                        "port": 0
+                    }
+                ]
+            }"#,
+        );
+        assert_eq!(line, 7);
+        assert_eq!(msg, "port of b must not be 0");
+    }
+
+    #[test]
+    fn type_error() {
+        let (line, msg) = error(
+            r#"{
+                "hosts": ["a"],
+                "wormgate_port": "8181"
+            }"#,
+        );
+        assert_eq!(line, 3);
+        assert!(msg.starts_with("invalid type: string"), "{}", msg);
+    }
+}
@@ -0,0 +1,130 @@
+//! Running a segment in the background
+//!
+//! A daemonized segment keeps the name it was started with, so `ps` shows it as what
+//! it is. It writes its pid to `poly-<run id>.pid` and its output to `poly-<run id>.log`
+//! in the run directory, which is POLY_RUN_DIR or `/tmp/poly`.
+
+use nix::unistd::{dup2, fork, getpid, setsid, ForkResult};
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use error::Result;
+
+/// Environment variable holding the directory for pidfiles and log files
+pub const RUN_DIR_VAR: &str = "POLY_RUN_DIR";
+
+/// Directory for pidfiles and log files if POLY_RUN_DIR is not set
+pub const DEFAULT_RUN_DIR: &str = "/tmp/poly";
+
+/// How the segment process runs
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Mode {
+    /// Detach from the terminal and log to the run directory
+    Daemon,
+    /// Stay attached to the terminal and log to stdout, for debugging
+    Foreground,
+}
+
+/// Where a segment keeps its pidfile and log file
+#[derive(Debug, Clone, PartialEq)]
+pub struct RunDir {
+    path: PathBuf,
+    run_id: String,
+}
+
+/// Pidfile of a running daemon, removed again when the daemon exits
+#[derive(Debug)]
+pub struct PidFile {
+    path: PathBuf,
+    pid: String,
+}
+
+/// Run directory from POLY_RUN_DIR, falling back to DEFAULT_RUN_DIR
+pub fn run_dir() -> PathBuf {
+    env::var_os(RUN_DIR_VAR).map_or_else(|| PathBuf::from(DEFAULT_RUN_DIR), PathBuf::from)
+}
+
+impl RunDir {
+    pub fn new<P: AsRef<Path>>(path: P, run_id: &str) -> RunDir {
+        RunDir {
+            path: path.as_ref().to_path_buf(),
+            run_id: String::from(run_id),
+        }
+    }
+
+    /// File the daemon writes its pid to
+    pub fn pidfile(&self) -> PathBuf {
+        self.path.join(format!("poly-{}.pid", self.run_id))
+    }
+
+    /// File the daemon writes its output to
+    pub fn logfile(&self) -> PathBuf {
+        self.path.join(format!("poly-{}.log", self.run_id))
+    }
+
+    /// Detach from the terminal, returning the pidfile in the daemon and None in the parent
+    ///
+    /// The daemon runs in a new session with stdin from /dev/null and stdout and stderr
+    /// appended to the log file. The parent should exit once this returns.
+    pub fn daemonize(&self) -> Result<Option<PidFile>> {
+        fs::create_dir_all(&self.path)?;
+        let log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.logfile())?;
+        let null = File::open("/dev/null")?;
+
+        // Nobody waits for the children of a daemon, so don't leave zombies around
+        let action = SigAction::new(SigHandler::SigIgn, SaFlags::empty(), SigSet::empty());
+        unsafe { sigaction(Signal::SIGCHLD, &action) }?;
+
+        io::stdout().flush()?;
+        match fork()? {
+            ForkResult::Parent { child } => {
+                info!("Started daemon with pid {}, logging to {:?}", child, self.logfile());
+                Ok(None)
+            }
+            ForkResult::Child => {
+                let _sid = setsid()?;
+                let _fd = dup2(null.as_raw_fd(), io::stdin().as_raw_fd())?;
+                let _fd = dup2(log.as_raw_fd(), io::stdout().as_raw_fd())?;
+                let _fd = dup2(log.as_raw_fd(), io::stderr().as_raw_fd())?;
+                PidFile::create(self.pidfile()).map(Some)
+            }
+        }
+    }
+}
+
+impl PidFile {
+    /// Write the pid of this process to path
+    pub fn create(path: PathBuf) -> Result<PidFile> {
+        let pid = getpid().to_string();
+        writeln!(File::create(&path)?, "{}", pid)?;
+        Ok(PidFile { path, pid })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for PidFile {
+    /// Remove the pidfile, unless a newer segment of the run took it over
+    fn drop(&mut self) {
+        let mut contents = String::new();
+        let ours = File::open(&self.path)
+            .and_then(|mut f| f.read_to_string(&mut contents))
+            .map(|_| contents.trim() == self.pid)
+            .unwrap_or(false);
+        if ours {
+            if let Err(e) = fs::remove_file(&self.path) {
+                warn!("Unable to remove pidfile {:?}: {}", self.path, e);
+            }
+        }
+    }
+}
@@ -0,0 +1,125 @@
+use nix;
+use reqwest;
+use serde_json;
+
+use wire;
+
+use std::error;
+use std::fmt;
+use std::io;
+use std::result;
+
+/// Errors that can occur while running a worm segment
+#[derive(Debug)]
+pub enum PolyError {
+    /// Socket or file operation failed
+    Io(io::Error),
+    /// Request to a wormgate failed
+    Http(reqwest::Error),
+    /// Unable to serialize or deserialize data
+    Json(serde_json::Error),
+    /// Hostname could not be resolved to an address
+    Resolve(String),
+    /// The config file at the path is malformed, the error being on the given line
+    ConfigFile(String, usize, String),
+    /// A peer sent something we did not expect
+    Protocol(String),
+    /// A peer speaks a different version of the wire protocol
+    Version(u8),
+    /// A frame was not authenticated with the cluster key
+    Auth(String),
+    /// The host is not on the allowlist of the run
+    Forbidden(String),
+    /// The run was aborted by an operator
+    Aborted(String),
+    /// A peer sent something belonging to a different run
+    WrongRun(String),
+    /// A setting has a value we cannot use
+    Config(String),
+}
+
+pub type Result<T> = result::Result<T, PolyError>;
+
+impl PolyError {
+    /// Determine if nothing was listening where we tried to connect
+    pub fn is_connection_refused(&self) -> bool {
+        match *self {
+            PolyError::Io(ref e) => e.kind() == io::ErrorKind::ConnectionRefused,
+            _ => false,
+        }
+    }
+
+    /// Determine if the peer did not reply in time
+    pub fn is_timeout(&self) -> bool {
+        match *self {
+            PolyError::Io(ref e) => {
+                e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut
+            }
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for PolyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PolyError::Io(ref e) => write!(f, "I/O error: {}", e),
+            PolyError::Http(ref e) => write!(f, "HTTP error: {}", e),
+            PolyError::Json(ref e) => write!(f, "JSON error: {}", e),
+            PolyError::Resolve(ref host) => write!(f, "Unable to resolve host: {}", host),
+            PolyError::ConfigFile(ref path, line, ref msg) => {
+                write!(f, "Invalid config file {} line {}: {}", path, line, msg)
+            }
+            PolyError::Protocol(ref msg) => write!(f, "Protocol error: {}", msg),
+            PolyError::Version(version) => write!(
+                f,
+                "Peer speaks protocol version {}, we speak version {}",
+                version,
+                wire::PROTOCOL_VERSION
+            ),
+            PolyError::Auth(ref msg) => write!(f, "Authentication failed: {}", msg),
+            PolyError::Forbidden(ref host) => write!(f, "Host {} is not on the allowlist", host),
+            PolyError::Aborted(ref run_id) => write!(f, "Run {} was aborted", run_id),
+            PolyError::WrongRun(ref run_id) => write!(f, "Peer belongs to run {}", run_id),
+            PolyError::Config(ref msg) => write!(f, "Invalid configuration: {}", msg),
+        }
+    }
+}
+
+impl error::Error for PolyError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            PolyError::Io(ref e) => Some(e),
+            PolyError::Http(ref e) => Some(e),
+            PolyError::Json(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for PolyError {
+    fn from(e: io::Error) -> PolyError {
+        PolyError::Io(e)
+    }
+}
+
+impl From<reqwest::Error> for PolyError {
+    fn from(e: reqwest::Error) -> PolyError {
+        PolyError::Http(e)
+    }
+}
+
+impl From<serde_json::Error> for PolyError {
+    fn from(e: serde_json::Error) -> PolyError {
+        PolyError::Json(e)
+    }
+}
+
+impl From<nix::Error> for PolyError {
+    fn from(e: nix::Error) -> PolyError {
+        match e {
+            nix::Error::Sys(errno) => PolyError::Io(errno.into()),
+            e => PolyError::Io(io::Error::other(e.to_string())),
+        }
+    }
+}
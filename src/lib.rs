@@ -0,0 +1,38 @@
+extern crate ed25519_dalek;
+extern crate hmac;
+extern crate nix;
+extern crate rand;
+extern crate reqwest;
+
+#[macro_use]
+extern crate serde_derive;
+
+extern crate serde;
+extern crate serde_json;
+extern crate sha2;
+
+#[macro_use]
+pub mod logging;
+
+pub mod agent;
+pub mod auth;
+pub mod backoff;
+pub mod cli;
+pub mod commands;
+pub mod config;
+pub mod daemon;
+pub mod error;
+pub mod placement;
+pub mod protocol;
+pub mod scheduler;
+pub mod sim;
+pub mod transport;
+pub mod wire;
+pub mod worm;
+pub mod wormgate;
+
+pub use agent::{Agent, Step};
+pub use error::{PolyError, Result};
+pub use protocol::{AbortAck, Message, SegmentStatus, TreeState, WormSegment};
+pub use transport::Transport;
+pub use worm::Worm;
@@ -0,0 +1,476 @@
+//! Logging with levels and the context of the segment doing the logging
+//!
+//! Every line names the run, host and role of the segment that logged it. Segments
+//! are started by the wormgate without arguments, so logging is set up from the
+//! environment: POLY_LOG sets the level, POLY_LOG_FORMAT=json switches to one JSON
+//! object per line and POLY_LOG_SINK picks where lines go: `stdout` (the default),
+//! `stderr`, `file:<path>` or `wormgate`, which posts them to `/log` on the wormgate of
+//! the segment, or on the given port with `wormgate:<port>`. Lines for the wormgate are
+//! posted by a thread of their own, so logging never waits for the wormgate. The thread
+//! is started by the first line, so a segment that daemonizes posts from the daemon.
+//!
+//! The context is kept per thread, since the simulator steps many segments on one.
+
+use reqwest;
+use serde_json;
+
+use std::cell::RefCell;
+use std::env;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use error::{PolyError, Result};
+
+/// Environment variable holding the most verbose level that is logged
+pub const LEVEL_VAR: &str = "POLY_LOG";
+
+/// Environment variable holding the format of log lines, `text` or `json`
+pub const FORMAT_VAR: &str = "POLY_LOG_FORMAT";
+
+/// Environment variable naming where log lines go
+pub const SINK_VAR: &str = "POLY_LOG_SINK";
+
+/// Path on the wormgate log lines are posted to
+pub const WORMGATE_LOG_PATH: &str = "/log";
+
+/// How long flush waits for the lines still on their way to the wormgate
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Log an error, something the segment could not recover from
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => {
+        $crate::logging::log($crate::logging::Level::Error, format_args!($($arg)*))
+    };
+}
+
+/// Log a warning, something that failed but the segment works around
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        $crate::logging::log($crate::logging::Level::Warn, format_args!($($arg)*))
+    };
+}
+
+/// Log something that happened to the run
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => {
+        $crate::logging::log($crate::logging::Level::Info, format_args!($($arg)*))
+    };
+}
+
+/// Log what the segment is doing, step by step
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        $crate::logging::log($crate::logging::Level::Debug, format_args!($($arg)*))
+    };
+}
+
+/// Log everything, including whole worms
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)*) => {
+        $crate::logging::log($crate::logging::Level::Trace, format_args!($($arg)*))
+    };
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+/// Where log lines go
+#[derive(Debug, Clone, PartialEq)]
+pub enum Sink {
+    Stdout,
+    Stderr,
+    File(PathBuf),
+    /// The wormgate on the given port, or else the one of the segment logging
+    Wormgate(Option<u16>),
+}
+
+/// Who is logging, included in every line
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Context {
+    pub run_id: String,
+    pub hostname: String,
+    pub role: String,
+    /// Wormgate the `wormgate` sink posts to
+    pub wormgate_port: Option<u16>,
+}
+
+/// A log line in JSON format
+#[derive(Serialize)]
+struct Record<'a> {
+    time: f64,
+    level: &'a str,
+    run_id: &'a str,
+    hostname: &'a str,
+    role: &'a str,
+    message: &'a str,
+}
+
+struct Logger {
+    level: Level,
+    format: Format,
+    sink: Sink,
+    file: Option<File>,
+    poster: Option<Poster>,
+}
+
+/// Thread posting lines to the wormgate, and the process that started it
+struct Poster {
+    pid: u32,
+    sender: Sender<Post>,
+}
+
+/// Work for the thread posting lines to the wormgate
+enum Post {
+    /// Post the line to the wormgate on the given port
+    Line(u16, String),
+    /// Report back once every line before this one is posted
+    Flush(Sender<()>),
+}
+
+static LOGGER: Mutex<Option<Logger>> = Mutex::new(None);
+
+thread_local! {
+    static CONTEXT: RefCell<Context> = RefCell::new(Context::default());
+}
+
+impl Level {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(&self.as_str().to_uppercase())
+    }
+}
+
+impl FromStr for Level {
+    type Err = PolyError;
+
+    fn from_str(s: &str) -> Result<Level> {
+        match s {
+            "error" => Ok(Level::Error),
+            "warn" => Ok(Level::Warn),
+            "info" => Ok(Level::Info),
+            "debug" => Ok(Level::Debug),
+            "trace" => Ok(Level::Trace),
+            _ => Err(PolyError::Config(format!("unknown log level {:?}", s))),
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = PolyError;
+
+    fn from_str(s: &str) -> Result<Format> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            _ => Err(PolyError::Config(format!("unknown log format {:?}", s))),
+        }
+    }
+}
+
+impl FromStr for Sink {
+    type Err = PolyError;
+
+    fn from_str(s: &str) -> Result<Sink> {
+        match s {
+            "stdout" => Ok(Sink::Stdout),
+            "stderr" => Ok(Sink::Stderr),
+            "wormgate" => Ok(Sink::Wormgate(None)),
+            _ if s.starts_with("file:") && s.len() > 5 => Ok(Sink::File(PathBuf::from(&s[5..]))),
+            _ if s.starts_with("wormgate:") => s[9..]
+                .parse()
+                .map(|port| Sink::Wormgate(Some(port)))
+                .map_err(|_| PolyError::Config(format!("invalid wormgate port in {:?}", s))),
+            _ => Err(PolyError::Config(format!("unknown log sink {:?}", s))),
+        }
+    }
+}
+
+impl Default for Logger {
+    fn default() -> Logger {
+        Logger {
+            level: Level::Info,
+            format: Format::Text,
+            sink: Sink::Stdout,
+            file: None,
+            poster: None,
+        }
+    }
+}
+
+/// Log lines up to level in format to sink
+pub fn init(level: Level, format: Format, sink: Sink) -> Result<()> {
+    let file = match sink {
+        Sink::File(ref path) => Some(OpenOptions::new().create(true).append(true).open(path)?),
+        _ => None,
+    };
+    *LOGGER.lock().expect("Logger poisoned") = Some(Logger {
+        level,
+        format,
+        sink,
+        file,
+        poster: None,
+    });
+    Ok(())
+}
+
+/// Wait for the lines logged so far to reach the wormgate, if that is where they go
+pub fn flush() {
+    let poster = LOGGER
+        .lock()
+        .expect("Logger poisoned")
+        .as_ref()
+        .and_then(Logger::running_poster);
+    if let Some(poster) = poster {
+        wait_for(&poster);
+    }
+}
+
+/// Set up logging from POLY_LOG, POLY_LOG_FORMAT and POLY_LOG_SINK
+pub fn init_from_env() -> Result<()> {
+    fn var<T: FromStr<Err = PolyError>>(name: &str, default: T) -> Result<T> {
+        match env::var(name) {
+            Ok(ref value) if !value.is_empty() => value.parse(),
+            _ => Ok(default),
+        }
+    }
+    init(
+        var(LEVEL_VAR, Level::Info)?,
+        var(FORMAT_VAR, Format::Text)?,
+        var(SINK_VAR, Sink::Stdout)?,
+    )
+}
+
+/// Log as context on this thread from now on
+pub fn set_context(context: Context) {
+    CONTEXT.with(|c| *c.borrow_mut() = context);
+}
+
+/// Context this thread logs as
+pub fn context() -> Context {
+    CONTEXT.with(|c| c.borrow().clone())
+}
+
+/// Log a line at level, use the macros instead
+pub fn log(level: Level, args: fmt::Arguments) {
+    let mut logger = LOGGER.lock().expect("Logger poisoned");
+    let logger = logger.get_or_insert_with(Logger::default);
+    if level > logger.level {
+        return;
+    }
+    CONTEXT.with(|context| logger.write(level, &context.borrow(), &args.to_string()));
+}
+
+/// Write a line formatted by a segment, as the wormgate does with lines posted to it
+///
+/// The line is written as it is, regardless of level. A wormgate never posts lines on
+/// to another wormgate, those go to stdout instead.
+pub fn forward(line: &str) {
+    let mut logger = LOGGER.lock().expect("Logger poisoned");
+    let logger = logger.get_or_insert_with(Logger::default);
+    if let Sink::Wormgate(_) = logger.sink {
+        println!("{}", line);
+    } else {
+        logger.emit(line, None);
+    }
+}
+
+impl Logger {
+    fn format(&self, level: Level, context: &Context, message: &str) -> String {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0.0, |since| since.as_secs_f64());
+        match self.format {
+            Format::Text => format!(
+                "{:.3} {:5} {} {} {}: {}",
+                time,
+                level,
+                or_dash(&context.run_id),
+                or_dash(&context.hostname),
+                or_dash(&context.role),
+                message
+            ),
+            Format::Json => serde_json::to_string(&Record {
+                time,
+                level: level.as_str(),
+                run_id: &context.run_id,
+                hostname: &context.hostname,
+                role: &context.role,
+                message,
+            }).unwrap_or_else(|e| format!("{{\"message\": \"unloggable line: {}\"}}", e)),
+        }
+    }
+
+    fn write(&mut self, level: Level, context: &Context, message: &str) {
+        let line = self.format(level, context, message);
+        self.emit(&line, context.wormgate_port);
+    }
+
+    /// Write a formatted line to the sink
+    fn emit(&mut self, line: &str, wormgate_port: Option<u16>) {
+        let result = match self.sink {
+            Sink::Stdout => writeln!(io::stdout(), "{}", line).map_err(PolyError::from),
+            Sink::Stderr => writeln!(io::stderr(), "{}", line).map_err(PolyError::from),
+            Sink::File(_) => match self.file {
+                Some(ref mut file) => writeln!(file, "{}", line).map_err(PolyError::from),
+                None => Ok(()),
+            },
+            Sink::Wormgate(port) => match port.or(wormgate_port) {
+                Some(port) => self
+                    .poster()
+                    .send(Post::Line(port, String::from(line)))
+                    .map_err(|_| PolyError::Protocol(String::from("log poster stopped"))),
+                None => Err(PolyError::Config(String::from("no wormgate to log to"))),
+            },
+        };
+        // Nowhere else to report a failing sink, so fall back to stderr
+        if let Err(e) = result {
+            let _ = writeln!(io::stderr(), "{} (unable to log to {:?}: {})", line, self.sink, e);
+        }
+    }
+
+    /// Thread posting to the wormgate, started if this process has none yet
+    ///
+    /// Threads do not survive a fork, so a forked process never posts through the one
+    /// its parent started.
+    fn poster(&mut self) -> &Sender<Post> {
+        if self.running_poster().is_none() {
+            self.poster = Some(Poster {
+                pid: process::id(),
+                sender: spawn_poster(),
+            });
+        }
+        &self.poster.as_ref().expect("Poster just started").sender
+    }
+
+    /// Thread posting to the wormgate, if this process started one
+    fn running_poster(&self) -> Option<Sender<Post>> {
+        self.poster
+            .as_ref()
+            .filter(|poster| poster.pid == process::id())
+            .map(|poster| poster.sender.clone())
+    }
+}
+
+fn or_dash(s: &str) -> &str {
+    if s.is_empty() {
+        "-"
+    } else {
+        s
+    }
+}
+
+/// Start the thread posting lines to the wormgate, with a client reused for every line
+fn spawn_poster() -> Sender<Post> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let client = reqwest::Client::new();
+        for post in receiver {
+            match post {
+                Post::Line(port, line) => {
+                    // Nowhere else to report a failing wormgate, so fall back to stderr
+                    if let Err(e) = post_line(&client, port, &line) {
+                        let _ = writeln!(io::stderr(), "{} (unable to post it: {})", line, e);
+                    }
+                }
+                Post::Flush(done) => {
+                    let _ = done.send(());
+                }
+            }
+        }
+    });
+    sender
+}
+
+/// Wait for the lines sent to poster so far to be posted
+fn wait_for(poster: &Sender<Post>) {
+    let (done, flushed) = mpsc::channel();
+    if poster.send(Post::Flush(done)).is_ok() {
+        let _ = flushed.recv_timeout(FLUSH_TIMEOUT);
+    }
+}
+
+/// Post a log line to the wormgate on this host
+fn post_line(client: &reqwest::Client, port: u16, line: &str) -> Result<()> {
+    let _res = client
+        .post(&format!("http://localhost:{}{}", port, WORMGATE_LOG_PATH))
+        .body(String::from(line))
+        .send()?
+        .error_for_status()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use wormgate::{FakeWormgate, Received};
+
+    #[test]
+    fn posts_to_wormgate() {
+        let wormgate = FakeWormgate::bind("127.0.0.1:0", "host", "observation").unwrap();
+        let _handle = wormgate.spawn().unwrap();
+        let mut logger = Logger {
+            sink: Sink::Wormgate(Some(wormgate.port().unwrap())),
+            ..Logger::default()
+        };
+        assert!(logger.running_poster().is_none());
+
+        let context = Context {
+            run_id: String::from("run"),
+            hostname: String::from("host"),
+            role: String::from("child"),
+            wormgate_port: None,
+        };
+        logger.write(Level::Info, &context, "first");
+        logger.write(Level::Warn, &context, "second");
+        wait_for(&logger.running_poster().expect("No poster started"));
+
+        let lines: Vec<String> = wormgate
+            .received()
+            .into_iter()
+            .filter_map(|r| match r {
+                Received::Log(line) => Some(line),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with("run host child: first"), "{}", lines[0]);
+        assert!(lines[1].contains(" WARN "), "{}", lines[1]);
+    }
+}
@@ -0,0 +1,136 @@
+//! Choosing the next host to infect
+//!
+//! Every strategy picks among the candidates of the worm: allowed hosts we have no
+//! observation from and no segment on, in the order of the hosts list.
+
+use rand::{Rng, SeedableRng, StdRng};
+
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Instant;
+
+use error::PolyError;
+use worm::Worm;
+
+/// Placement strategy the segments of a run use, chosen by the start command
+#[derive(Deserialize, Serialize, Debug, Default, Copy, Clone, PartialEq)]
+pub enum PlacementKind {
+    #[default]
+    InOrder,
+    Random,
+    LeastRecentlyTried,
+    PrefixAware,
+}
+
+/// Picks the host the next segment is sent to
+pub trait PlacementStrategy: fmt::Debug {
+    /// Pick one of the candidates, or none to leave the hosts alone for now
+    fn choose(&mut self, worm: &Worm, candidates: &[String]) -> Option<String>;
+
+    /// Note that we tried to infect host
+    fn tried(&mut self, _host: &str, _now: Instant) {}
+}
+
+/// Picks the first candidate, the way segments always did
+#[derive(Debug, Default)]
+pub struct InOrder;
+
+/// Picks any of the candidates
+#[derive(Debug)]
+pub struct RandomPlacement {
+    rng: StdRng,
+}
+
+/// Picks a candidate we never tried, or else the one we tried longest ago
+#[derive(Debug, Default)]
+pub struct LeastRecentlyTried {
+    tried: HashMap<String, Instant>,
+}
+
+/// Spreads segments over racks, picking a candidate from the rack with fewest segments
+///
+/// The rack of a host is its name up to the last `-`, so `compute-1-5` is in rack
+/// `compute-1`, or when there is no `-` its name without trailing digits.
+#[derive(Debug, Default)]
+pub struct PrefixAware;
+
+impl PlacementKind {
+    /// Create a strategy of this kind, seeding it for the random choices it makes
+    pub fn build(self, seed: usize) -> Box<dyn PlacementStrategy> {
+        match self {
+            PlacementKind::InOrder => Box::new(InOrder),
+            PlacementKind::Random => Box::new(RandomPlacement::new(seed)),
+            PlacementKind::LeastRecentlyTried => Box::new(LeastRecentlyTried::default()),
+            PlacementKind::PrefixAware => Box::new(PrefixAware),
+        }
+    }
+}
+
+impl FromStr for PlacementKind {
+    type Err = PolyError;
+
+    fn from_str(s: &str) -> Result<PlacementKind, PolyError> {
+        match s {
+            "in-order" => Ok(PlacementKind::InOrder),
+            "random" => Ok(PlacementKind::Random),
+            "least-recently-tried" | "lrt" => Ok(PlacementKind::LeastRecentlyTried),
+            "prefix" | "prefix-aware" => Ok(PlacementKind::PrefixAware),
+            _ => Err(PolyError::Config(format!("unknown placement {:?}", s))),
+        }
+    }
+}
+
+impl PlacementStrategy for InOrder {
+    fn choose(&mut self, _worm: &Worm, candidates: &[String]) -> Option<String> {
+        candidates.first().cloned()
+    }
+}
+
+impl RandomPlacement {
+    pub fn new(seed: usize) -> RandomPlacement {
+        RandomPlacement {
+            rng: StdRng::from_seed(&[seed][..]),
+        }
+    }
+}
+
+impl PlacementStrategy for RandomPlacement {
+    fn choose(&mut self, _worm: &Worm, candidates: &[String]) -> Option<String> {
+        self.rng.choose(candidates).cloned()
+    }
+}
+
+impl PlacementStrategy for LeastRecentlyTried {
+    fn choose(&mut self, _worm: &Worm, candidates: &[String]) -> Option<String> {
+        candidates
+            .iter()
+            .min_by_key(|host| self.tried.get(*host))
+            .cloned()
+    }
+
+    fn tried(&mut self, host: &str, now: Instant) {
+        self.tried.insert(String::from(host), now);
+    }
+}
+
+/// Rack a host belongs to
+pub fn rack(hostname: &str) -> &str {
+    match hostname.rfind('-') {
+        Some(index) => &hostname[..index],
+        None => hostname.trim_end_matches(|c: char| c.is_ascii_digit()),
+    }
+}
+
+impl PlacementStrategy for PrefixAware {
+    fn choose(&mut self, worm: &Worm, candidates: &[String]) -> Option<String> {
+        let mut segments = HashMap::new();
+        for segment in &worm.current_segments {
+            *segments.entry(rack(&segment.hostname)).or_insert(0) += 1;
+        }
+        candidates
+            .iter()
+            .min_by_key(|host| segments.get(rack(host)).cloned().unwrap_or(0))
+            .cloned()
+    }
+}
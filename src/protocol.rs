@@ -0,0 +1,220 @@
+use std::collections::HashMap;
+use std::env;
+
+use backoff::RetryPolicy;
+use placement::PlacementKind;
+use scheduler::SchedulerKind;
+
+/// Environment variable naming the run a segment belongs to
+pub const RUN_ID_VAR: &str = "POLY_RUN_ID";
+
+/// HTTP header telling the wormgate which run an uploaded segment belongs to
+///
+/// Wormgates pass it on to the segment they launch in POLY_RUN_ID.
+pub const RUN_ID_HEADER: &str = "X-Poly-Run-Id";
+
+/// Run a segment belongs to when none is named
+pub const DEFAULT_RUN_ID: &str = "default";
+
+#[derive(Deserialize, Serialize, Debug, Copy, Clone, PartialEq)]
+pub enum TreeState {
+    Child,
+    Parent,
+    Sibling,
+    This,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub enum Message {
+    SuicideNote(WormSegment),
+    NewSegment(WormSegment),
+    WantData(String),
+    Observation(Option<String>),
+    GatheringCompleted,
+    /// Observations handed to the initial segment by a segment that finished elsewhere
+    PushData(HashMap<String, String>),
+    /// Kick off a new gathering run from the segment receiving it
+    ///
+    /// The run is the one named by the envelope. The run never contacts hosts outside
+    /// allowed_hosts, which defaults to hosts.
+    /// Segments give up and return what they have after ttl_secs or max_hops state transfers.
+    /// Hosts in wormgate_ports have their wormgate on that port instead of wormgate_port.
+    Start {
+        wormgate_port: u16,
+        hosts: Vec<String>,
+        max_segments: usize,
+        #[serde(default)]
+        allowed_hosts: Vec<String>,
+        #[serde(default)]
+        ttl_secs: Option<u64>,
+        #[serde(default)]
+        max_hops: Option<usize>,
+        #[serde(default)]
+        scheduler: SchedulerKind,
+        #[serde(default)]
+        placement: PlacementKind,
+        #[serde(default)]
+        wormgate_ports: HashMap<String, u16>,
+        #[serde(default)]
+        gossip_fanout: Option<usize>,
+        #[serde(default)]
+        retry: RetryPolicy,
+    },
+    /// Stop every segment of the run, passed on through the known segments
+    ///
+    /// Segments in seen already acknowledged the abort and are not asked again. The sender
+    /// waits wait_ms for the acknowledgement, so that is how long the segments the abort
+    /// is passed on to may take to acknowledge it in turn.
+    Abort {
+        run_id: String,
+        #[serde(default)]
+        seen: Vec<String>,
+        #[serde(default)]
+        wait_ms: u64,
+    },
+    /// Reply to Abort from a segment and every segment it passed the abort on to in time
+    AbortAck(AbortAck),
+    /// Reply to a state transfer or start command once the new segment decoded it
+    ///
+    /// The state hash is the hex encoded SHA-256 digest of the payload it received.
+    StateAck {
+        hostname: String,
+        state_hash: String,
+    },
+    /// Greeting opening every connection, answered with Hello by a segment of the same run
+    Hello,
+    /// Answer to a greeting from a different run, naming the run of the answering segment
+    WrongRun(String),
+    /// Ask a segment what it knows about the run
+    Status,
+    /// Reply to Status
+    StatusReport(SegmentStatus),
+}
+
+/// A message together with the run it belongs to, as it is sent between segments
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Envelope {
+    pub run_id: String,
+    pub message: Message,
+}
+
+/// Acknowledgement of an abort by a segment and the segments it passed the abort on to
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct AbortAck {
+    pub hostname: String,
+    pub children: Vec<AbortAck>,
+    pub unreachable: Vec<String>, // Segments we were unable to pass the abort on to
+    #[serde(default)]
+    pub unconfirmed: Vec<String>, // Segments that got the abort but did not acknowledge in time
+}
+
+/// What a segment knows about the run, as reported to Status
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct SegmentStatus {
+    pub current_hostname: String,
+    pub initial_hostname: String,
+    pub current_segments: Vec<WormSegment>,
+    pub cur_num_segments: usize,
+    pub max_num_segments: usize,
+    pub observed_hosts: Vec<String>, // Hosts we have observation data of, sorted
+    pub suicide_counter: usize,
+    pub uptime_secs: u64,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct WormSegment {
+    pub relationship: TreeState,
+    pub hostname: String,
+}
+
+impl AbortAck {
+    /// Create an acknowledgement from the segment on hostname
+    pub fn new(hostname: &str) -> AbortAck {
+        AbortAck {
+            hostname: String::from(hostname),
+            children: Vec::new(),
+            unreachable: Vec::new(),
+            unconfirmed: Vec::new(),
+        }
+    }
+
+    /// Hostnames of every segment that acknowledged the abort
+    pub fn hostnames(&self) -> Vec<String> {
+        let mut hostnames = vec![self.hostname.clone()];
+        for child in &self.children {
+            hostnames.extend(child.hostnames());
+        }
+        hostnames
+    }
+
+    /// Segments nobody was able to pass the abort on to
+    pub fn unreachable(&self) -> Vec<String> {
+        let mut unreachable = self.unacknowledged(&|ack| &ack.unreachable);
+        let unconfirmed = self.unconfirmed();
+        unreachable.retain(|host| !unconfirmed.contains(host));
+        unreachable
+    }
+
+    /// Segments that got the abort but never acknowledged it
+    pub fn unconfirmed(&self) -> Vec<String> {
+        self.unacknowledged(&|ack| &ack.unconfirmed)
+    }
+
+    /// Hosts listed by field anywhere in the tree that did not acknowledge, sorted
+    fn unacknowledged(&self, field: &dyn Fn(&AbortAck) -> &Vec<String>) -> Vec<String> {
+        let acked = self.hostnames();
+        let mut hosts = Vec::new();
+        self.collect(field, &mut hosts);
+        hosts.retain(|host| !acked.contains(host));
+        hosts.sort();
+        hosts.dedup();
+        hosts
+    }
+
+    fn collect(&self, field: &dyn Fn(&AbortAck) -> &Vec<String>, hosts: &mut Vec<String>) {
+        hosts.extend(field(self).iter().cloned());
+        for child in &self.children {
+            child.collect(field, hosts);
+        }
+    }
+}
+
+/// Read the run this segment belongs to from the environment
+pub fn run_id() -> String {
+    env::var(RUN_ID_VAR)
+        .ok()
+        .filter(|run_id| !run_id.is_empty())
+        .unwrap_or_else(|| String::from(DEFAULT_RUN_ID))
+}
+
+impl WormSegment {
+    /// Create a new WormSegment based on a state and host
+    pub fn new(rel: TreeState, host: &str) -> WormSegment {
+        WormSegment {
+            relationship: rel,
+            hostname: String::from(host),
+        }
+    }
+
+    /// Convert WormSegment such that we can send it to another segment
+    pub fn send_to(&self, target: &WormSegment) -> WormSegment {
+        let new_rel = match target.relationship {
+            TreeState::Child => match self.relationship {
+                TreeState::Child => TreeState::Sibling,
+                TreeState::This => TreeState::Parent,
+                _ => self.relationship,
+            },
+            TreeState::Sibling => match self.relationship {
+                TreeState::This => TreeState::Sibling,
+                _ => self.relationship,
+            },
+            _ => self.relationship,
+        };
+
+        if target.hostname == self.hostname {
+            WormSegment::new(TreeState::This, &self.hostname)
+        } else {
+            WormSegment::new(new_rel, &self.hostname)
+        }
+    }
+}
@@ -0,0 +1,265 @@
+//! Choosing what the main loop of a segment does next
+//!
+//! A segment either infects another host, listens for gossip or queries other
+//! segments for their observations. The event driven scheduler makes that choice
+//! from what happened to the segment, the random one rolls a die like the original
+//! main loop did and is kept around for comparison.
+
+use rand::{Rng, SeedableRng, StdRng};
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use error::PolyError;
+use worm::Worm;
+
+/// How long the event driven scheduler waits before querying segments again
+const QUERY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Something that happened to a segment since the last step
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// We learned about a segment on the given host
+    NewSegment(String),
+    /// We got the observation of the given host
+    DataArrived(String),
+    /// The segment on the given host died
+    PeerDied(String),
+}
+
+/// Action the main loop takes in a step
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Action {
+    Infect,
+    Listen,
+    Query,
+}
+
+/// Scheduler the segments of a run use, chosen by the start command
+#[derive(Deserialize, Serialize, Debug, Default, Copy, Clone, PartialEq)]
+pub enum SchedulerKind {
+    #[default]
+    EventDriven,
+    Random,
+}
+
+/// Decides which action the main loop takes next
+pub trait Scheduler: fmt::Debug {
+    /// Note something that happened to the segment
+    fn notify(&mut self, event: &Event);
+
+    /// Pick the action for the next step
+    fn next_action(&mut self, worm: &Worm, now: Instant) -> Action;
+}
+
+/// Infects while there is a free host, and otherwise queries when there is something
+/// new to query for, listening for gossip in between
+///
+/// Data pushed to us postpones the next query, since the segments that are done hand
+/// over their data anyway, and hosts we have data of are never queried. A dead peer
+/// whose data we are missing is replaced before anything else, even when the segments
+/// we count leave no room, as the count may include segments that are long gone.
+#[derive(Debug)]
+pub struct EventDriven {
+    query_due: bool,
+    last_query: Option<Instant>,
+    postpone_query: bool,
+    dead_peers: Vec<String>, // Hosts of dead peers that may need replacing
+}
+
+/// Picks one of the actions at random
+#[derive(Debug)]
+pub struct RandomScheduler {
+    rng: StdRng,
+}
+
+impl SchedulerKind {
+    /// Create a scheduler of this kind, seeding it for the random choices it makes
+    pub fn build(self, seed: usize) -> Box<dyn Scheduler> {
+        match self {
+            SchedulerKind::EventDriven => Box::new(EventDriven::new()),
+            SchedulerKind::Random => Box::new(RandomScheduler::new(seed)),
+        }
+    }
+}
+
+impl FromStr for SchedulerKind {
+    type Err = PolyError;
+
+    fn from_str(s: &str) -> Result<SchedulerKind, PolyError> {
+        match s {
+            "event" | "event-driven" => Ok(SchedulerKind::EventDriven),
+            "random" => Ok(SchedulerKind::Random),
+            _ => Err(PolyError::Config(format!("unknown scheduler {:?}", s))),
+        }
+    }
+}
+
+impl EventDriven {
+    pub fn new() -> EventDriven {
+        EventDriven {
+            query_due: true,
+            last_query: None,
+            postpone_query: false,
+            dead_peers: Vec::new(),
+        }
+    }
+}
+
+impl Default for EventDriven {
+    fn default() -> EventDriven {
+        EventDriven::new()
+    }
+}
+
+impl Scheduler for EventDriven {
+    fn notify(&mut self, event: &Event) {
+        match *event {
+            Event::NewSegment(_) => self.query_due = true,
+            Event::DataArrived(_) => self.postpone_query = true,
+            Event::PeerDied(ref host) => self.dead_peers.push(host.clone()),
+        }
+    }
+
+    fn next_action(&mut self, worm: &Worm, now: Instant) -> Action {
+        if self.postpone_query {
+            self.postpone_query = false;
+            self.query_due = false;
+            self.last_query = Some(now);
+        } else if self.last_query
+            .is_some_and(|last_query| now >= last_query + QUERY_INTERVAL)
+        {
+            self.query_due = true;
+        }
+
+        let candidates = worm.candidates(now);
+        // Peers that died after handing over their data need no replacing
+        self.dead_peers
+            .retain(|host| !worm.observation_data.contains_key(host));
+        if !self.dead_peers.is_empty() && !candidates.is_empty() {
+            debug!("Peers on {:?} died - replacing them", self.dead_peers);
+            self.dead_peers.clear();
+            return Action::Infect;
+        }
+
+        if worm.should_infect() && !candidates.is_empty() {
+            Action::Infect
+        } else if self.query_due && worm.has_missing_data() {
+            self.query_due = false;
+            self.last_query = Some(now);
+            Action::Query
+        } else {
+            Action::Listen
+        }
+    }
+}
+
+impl RandomScheduler {
+    pub fn new(seed: usize) -> RandomScheduler {
+        RandomScheduler {
+            rng: StdRng::from_seed(&[seed][..]),
+        }
+    }
+}
+
+impl Scheduler for RandomScheduler {
+    fn notify(&mut self, _event: &Event) {}
+
+    fn next_action(&mut self, _worm: &Worm, _now: Instant) -> Action {
+        match self.rng.gen::<u8>() % 3 {
+            0 => Action::Infect,
+            1 => Action::Listen,
+            _ => Action::Query,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use protocol::{TreeState, WormSegment};
+
+    /// Worm on a with a segment on b, at its limit of two segments and with c left
+    fn worm() -> Worm {
+        let hosts = vec![String::from("a"), String::from("b"), String::from("c")];
+        let mut worm = Worm::new("run", "a", 2, 8181, hosts);
+        worm.observation_data
+            .insert(String::from("a"), String::from("observation"));
+        worm.current_segments
+            .push(WormSegment::new(TreeState::Child, "b"));
+        worm.cur_num_segments = 2;
+        worm
+    }
+
+    #[test]
+    fn queries_first_then_listens() {
+        let worm = worm();
+        let now = Instant::now();
+        let mut scheduler = EventDriven::new();
+        assert_eq!(scheduler.next_action(&worm, now), Action::Query);
+        assert_eq!(scheduler.next_action(&worm, now), Action::Listen);
+    }
+
+    #[test]
+    fn infects_while_there_is_room() {
+        let mut worm = worm();
+        worm.max_num_segments = 3;
+        let mut scheduler = EventDriven::new();
+        assert_eq!(scheduler.next_action(&worm, Instant::now()), Action::Infect);
+    }
+
+    #[test]
+    fn new_segment_makes_query_due() {
+        let worm = worm();
+        let now = Instant::now();
+        let mut scheduler = EventDriven::new();
+        assert_eq!(scheduler.next_action(&worm, now), Action::Query);
+        scheduler.notify(&Event::NewSegment(String::from("b")));
+        assert_eq!(scheduler.next_action(&worm, now), Action::Query);
+    }
+
+    #[test]
+    fn data_arrived_postpones_query() {
+        let worm = worm();
+        let now = Instant::now();
+        let mut scheduler = EventDriven::new();
+        scheduler.notify(&Event::NewSegment(String::from("b")));
+        scheduler.notify(&Event::DataArrived(String::from("c")));
+        assert_eq!(scheduler.next_action(&worm, now), Action::Listen);
+        assert_eq!(scheduler.next_action(&worm, now + QUERY_INTERVAL), Action::Query);
+    }
+
+    #[test]
+    fn queries_again_after_interval() {
+        let worm = worm();
+        let now = Instant::now();
+        let mut scheduler = EventDriven::new();
+        assert_eq!(scheduler.next_action(&worm, now), Action::Query);
+        let soon = now + QUERY_INTERVAL / 2;
+        assert_eq!(scheduler.next_action(&worm, soon), Action::Listen);
+        assert_eq!(scheduler.next_action(&worm, now + QUERY_INTERVAL), Action::Query);
+    }
+
+    #[test]
+    fn peer_died_is_replaced_first() {
+        let worm = worm();
+        assert!(!worm.should_infect());
+        let now = Instant::now();
+        let mut scheduler = EventDriven::new();
+        scheduler.notify(&Event::PeerDied(String::from("c")));
+        assert_eq!(scheduler.next_action(&worm, now), Action::Infect);
+        assert_eq!(scheduler.next_action(&worm, now), Action::Query);
+    }
+
+    #[test]
+    fn peer_died_with_data_is_not_replaced() {
+        let mut worm = worm();
+        worm.observation_data
+            .insert(String::from("c"), String::from("observation"));
+        let mut scheduler = EventDriven::new();
+        scheduler.notify(&Event::PeerDied(String::from("c")));
+        assert_eq!(scheduler.next_action(&worm, Instant::now()), Action::Query);
+    }
+}
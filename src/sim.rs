@@ -0,0 +1,265 @@
+use rand::{Rng, SeedableRng, StdRng};
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::rc::Rc;
+use std::time::Duration;
+
+use agent::{Agent, Step};
+use backoff::RetryPolicy;
+use placement::PlacementKind;
+use protocol::Message;
+use scheduler::SchedulerKind;
+use transport::{MemoryNetwork, Transport};
+use worm::Worm;
+
+/// Run simulated segments belong to, unlike real runs not the default one
+pub const SIM_RUN_ID: &str = "sim";
+
+/// Parameters of a simulated gathering run
+#[derive(Debug, Clone)]
+pub struct SimConfig {
+    /// Run the segments belong to
+    pub run_id: String,
+    /// Number of virtual hosts, the first one is the initial host
+    pub hosts: usize,
+    /// Number of racks the hosts are spread over, named `rack<n>-host<m>` when above one
+    pub racks: usize,
+    /// Seed for the random choices made by the segments
+    pub seed: usize,
+    /// Give up after this many rounds
+    pub max_rounds: usize,
+    /// Virtual time that passes each round
+    pub tick: Duration,
+    /// Virtual seconds after which the segments give up on the run
+    pub ttl_secs: Option<u64>,
+    /// State transfers after which a worm gives up on the run
+    pub max_hops: Option<usize>,
+    /// How segments decide what to do next
+    pub scheduler: SchedulerKind,
+    /// How segments pick the next host to infect
+    pub placement: PlacementKind,
+}
+
+/// Outcome of a simulated gathering run
+#[derive(Debug)]
+pub struct SimReport {
+    /// Observation data returned to the wormgate on the initial host
+    pub returned_data: Option<HashMap<String, String>>,
+    /// All observations made it back to the initial host
+    pub completed: bool,
+    pub rounds: usize,
+    pub elapsed: Duration,
+    pub messages_sent: usize,
+    pub segments_spawned: usize,
+    pub segments_died: usize,
+}
+
+/// Deterministic simulation of a cluster of virtual hosts with fake wormgates
+pub struct Simulation {
+    config: SimConfig,
+    network: MemoryNetwork,
+    hostnames: Vec<String>,
+    segments: BTreeMap<String, Rc<RefCell<Agent>>>,
+    rng: StdRng,
+    segments_spawned: usize,
+    segments_died: usize,
+}
+
+impl Default for SimConfig {
+    fn default() -> SimConfig {
+        SimConfig {
+            run_id: String::from(SIM_RUN_ID),
+            hosts: 5,
+            racks: 1,
+            seed: 1,
+            max_rounds: 1000,
+            tick: Duration::from_secs(1),
+            ttl_secs: None,
+            max_hops: None,
+            scheduler: SchedulerKind::default(),
+            placement: PlacementKind::default(),
+        }
+    }
+}
+
+impl fmt::Display for SimReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Completed:        {}", self.completed)?;
+        writeln!(f, "Rounds:           {}", self.rounds)?;
+        writeln!(f, "Virtual time:     {:?}", self.elapsed)?;
+        writeln!(f, "Messages sent:    {}", self.messages_sent)?;
+        writeln!(f, "Segments spawned: {}", self.segments_spawned)?;
+        write!(f, "Segments died:    {}", self.segments_died)
+    }
+}
+
+impl Simulation {
+    /// Create the virtual hosts and start the initial segment on the first one
+    pub fn new(config: SimConfig) -> Simulation {
+        let network = MemoryNetwork::new().with_run_id(&config.run_id);
+        let hostnames: Vec<String> = (0..config.hosts)
+            .map(|i| match config.racks {
+                0 | 1 => format!("host{}", i),
+                racks => format!("rack{}-host{}", i % racks, i),
+            })
+            .collect();
+        for hostname in &hostnames {
+            network.add_host(hostname, &format!("observation from {}", hostname));
+        }
+
+        let rng = StdRng::from_seed(&[config.seed][..]);
+        let sim = Simulation {
+            config,
+            network,
+            hostnames,
+            segments: BTreeMap::new(),
+            rng,
+            segments_spawned: 0,
+            segments_died: 0,
+        };
+        if let Some(initial) = sim.hostnames.first() {
+            let transport = sim.network.transport(initial);
+            let start = Message::Start {
+                wormgate_port: 0,
+                hosts: sim.hostnames.clone(),
+                max_segments: sim.hostnames.len(),
+                allowed_hosts: sim.hostnames.clone(),
+                ttl_secs: sim.config.ttl_secs,
+                max_hops: sim.config.max_hops,
+                scheduler: sim.config.scheduler,
+                placement: sim.config.placement,
+                wormgate_ports: HashMap::new(),
+                gossip_fanout: None,
+                retry: RetryPolicy::default(),
+            };
+            if let Err(e) = transport.send_start(initial, &start) {
+                warn!("Unable to start the run on {}: {}", initial, e);
+            }
+        }
+        sim
+    }
+
+    /// Run until every segment has exited or the round limit is reached
+    pub fn run(mut self) -> SimReport {
+        let mut rounds = 0;
+        while rounds < self.config.max_rounds && !self.is_idle() {
+            self.round();
+            rounds += 1;
+        }
+
+        let returned_data = self.hostnames
+            .first()
+            .and_then(|initial| self.network.returned_data(initial));
+        let completed = returned_data.as_ref().is_some_and(|data| {
+            self.hostnames.iter().all(|host| data.contains_key(host))
+        });
+        SimReport {
+            returned_data,
+            completed,
+            rounds,
+            elapsed: self.network.elapsed(),
+            messages_sent: self.network.messages_sent(),
+            segments_spawned: self.segments_spawned,
+            segments_died: self.segments_died,
+        }
+    }
+
+    /// No segment is running and no worm is waiting for its segment to be spawned
+    fn is_idle(&self) -> bool {
+        self.segments.is_empty()
+            && self.hostnames
+                .iter()
+                .all(|host| self.network.pending_worms(host) == 0)
+    }
+
+    /// Spawn segments for the worms that arrived, then step every segment once
+    fn round(&mut self) {
+        for hostname in self.hostnames.clone() {
+            if self.segments.contains_key(&hostname) {
+                continue;
+            }
+            if let Some(worm) = self.network.take_worm(&hostname) {
+                self.spawn(&hostname, worm);
+            }
+        }
+
+        let mut order: Vec<String> = self.segments.keys().cloned().collect();
+        self.rng.shuffle(&mut order);
+        for hostname in order {
+            let transport = self.network.transport(&hostname);
+            let step = match self.segments.get(&hostname) {
+                Some(agent) => agent.borrow_mut().step(&transport),
+                None => continue,
+            };
+            if step == Step::Exit {
+                self.kill(&hostname);
+            }
+        }
+
+        self.network.advance(self.config.tick);
+    }
+
+    fn spawn(&mut self, hostname: &str, worm: Worm) {
+        let transport = self.network.transport(hostname);
+        let agent = Rc::new(RefCell::new(Agent::seeded(worm, self.rng.gen())));
+        self.segments_spawned += 1;
+        if agent.borrow_mut().start(&transport).is_err() {
+            self.segments_died += 1;
+            return;
+        }
+        if self.network.attach(hostname, agent.clone()).is_ok() {
+            self.segments.insert(String::from(hostname), agent);
+        }
+    }
+
+    fn kill(&mut self, hostname: &str) {
+        self.segments.remove(hostname);
+        let _res = self.network.detach(hostname);
+        self.segments_died += 1;
+    }
+}
+
+/// Run a simulation with the given configuration
+pub fn simulate(config: SimConfig) -> SimReport {
+    Simulation::new(config).run()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use logging::{self, Format, Level, Sink};
+
+    fn config(seed: usize) -> SimConfig {
+        // The segments of a simulation log plenty, keep the test output readable
+        logging::init(Level::Error, Format::Text, Sink::Stderr).unwrap();
+        SimConfig {
+            hosts: 8,
+            seed,
+            ..SimConfig::default()
+        }
+    }
+
+    #[test]
+    fn seeded_run_completes() {
+        let report = simulate(config(7));
+        assert!(report.completed, "{}", report);
+        let data = report.returned_data.expect("No data returned");
+        assert_eq!(data.len(), 8);
+        assert_eq!(data["host3"], "observation from host3");
+    }
+
+    #[test]
+    fn same_seed_same_run() {
+        let first = simulate(config(3));
+        let second = simulate(config(3));
+        assert_eq!(first.rounds, second.rounds);
+        assert_eq!(first.elapsed, second.elapsed);
+        assert_eq!(first.messages_sent, second.messages_sent);
+        assert_eq!(first.segments_spawned, second.segments_spawned);
+        assert_eq!(first.segments_died, second.segments_died);
+        assert_eq!(first.returned_data, second.returned_data);
+    }
+}
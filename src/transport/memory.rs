@@ -0,0 +1,387 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::rc::Rc;
+use std::time::{Duration, Instant, SystemTime};
+
+use agent::Agent;
+use error::{PolyError, Result};
+use protocol::{self, Message};
+use wire::{self, Frame, FrameKind};
+use worm::Worm;
+use super::{listen_for_worm, Transport};
+
+/// A single virtual host with its fake wormgate
+#[derive(Default)]
+struct MemoryHost {
+    inbox: VecDeque<Message>,
+    states: VecDeque<Vec<u8>>, // State transfers on their way to the waiting segment
+    ack: Option<Message>,      // What the waiting segment answered the last one with
+    worms: VecDeque<Worm>,     // Worms accepted, waiting for their segment to be spawned
+    observation: String,
+    returned_data: Option<HashMap<String, String>>,
+    uploads: usize,
+    segment: Option<Rc<RefCell<Agent>>>,
+}
+
+/// In-process network of virtual hosts, shared by all transports created from it
+///
+/// Messages are queued until the receiving segment listens, while requests are answered
+/// right away by the segment attached to the receiving host. State transfers are taken
+/// by the receiving host right away as well, and the worms it accepts wait there until
+/// their segment is spawned. Time only moves when the virtual clock is advanced or a
+/// segment sleeps.
+#[derive(Clone)]
+pub struct MemoryNetwork {
+    run_id: String,
+    hosts: Rc<RefCell<HashMap<String, MemoryHost>>>,
+    start: Instant,
+    start_time: SystemTime,
+    elapsed: Rc<Cell<Duration>>,
+    messages_sent: Rc<Cell<usize>>,
+}
+
+/// Transport used by a segment running on a host in a MemoryNetwork
+pub struct MemoryTransport {
+    hostname: String,
+    network: MemoryNetwork,
+}
+
+impl MemoryNetwork {
+    /// Create an empty network
+    pub fn new() -> MemoryNetwork {
+        MemoryNetwork {
+            run_id: String::from(protocol::DEFAULT_RUN_ID),
+            hosts: Rc::new(RefCell::new(HashMap::new())),
+            start: Instant::now(),
+            start_time: SystemTime::now(),
+            elapsed: Rc::new(Cell::new(Duration::from_secs(0))),
+            messages_sent: Rc::new(Cell::new(0)),
+        }
+    }
+
+    /// Run the segments of this network belong to, instead of the default run
+    pub fn with_run_id(mut self, run_id: &str) -> MemoryNetwork {
+        self.run_id = String::from(run_id);
+        self
+    }
+
+    /// Move the virtual clock forward
+    pub fn advance(&self, duration: Duration) {
+        self.elapsed.set(self.elapsed.get() + duration);
+    }
+
+    /// Virtual time passed since the network was created
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed.get()
+    }
+
+    /// Number of messages, requests, state transfers and uploads sent so far
+    pub fn messages_sent(&self) -> usize {
+        self.messages_sent.get()
+    }
+
+    /// Add a host whose wormgate serves the given observation
+    pub fn add_host(&self, hostname: &str, observation: &str) {
+        self.hosts.borrow_mut().insert(
+            String::from(hostname),
+            MemoryHost {
+                observation: String::from(observation),
+                ..MemoryHost::default()
+            },
+        );
+    }
+
+    /// Create a transport for a segment running on hostname
+    pub fn transport(&self, hostname: &str) -> MemoryTransport {
+        MemoryTransport {
+            hostname: String::from(hostname),
+            network: self.clone(),
+        }
+    }
+
+    /// Attach the segment running on hostname so it can receive messages
+    pub fn attach(&self, hostname: &str, segment: Rc<RefCell<Agent>>) -> Result<()> {
+        self.with_host(hostname, |host| {
+            host.segment = Some(segment);
+            Ok(())
+        })
+    }
+
+    /// Detach the segment running on hostname, dropping messages it did not read
+    pub fn detach(&self, hostname: &str) -> Result<()> {
+        self.with_host(hostname, |host| {
+            host.segment = None;
+            host.inbox.clear();
+            Ok(())
+        })
+    }
+
+    /// Number of times a binary was uploaded to the wormgate on hostname
+    pub fn uploads(&self, hostname: &str) -> usize {
+        self.with_host(hostname, |host| Ok(host.uploads))
+            .unwrap_or(0)
+    }
+
+    /// Number of worms accepted on hostname that wait for their segment to be spawned
+    pub fn pending_worms(&self, hostname: &str) -> usize {
+        self.with_host(hostname, |host| Ok(host.worms.len()))
+            .unwrap_or(0)
+    }
+
+    /// Take the next worm accepted on hostname, to spawn its segment
+    pub fn take_worm(&self, hostname: &str) -> Option<Worm> {
+        self.with_host(hostname, |host| Ok(host.worms.pop_front()))
+            .unwrap_or(None)
+    }
+
+    /// Observation data posted to the wormgate on hostname, if any
+    pub fn returned_data(&self, hostname: &str) -> Option<HashMap<String, String>> {
+        self.with_host(hostname, |host| Ok(host.returned_data.clone()))
+            .unwrap_or(None)
+    }
+
+    fn count_message(&self) {
+        self.messages_sent.set(self.messages_sent.get() + 1);
+    }
+
+    fn with_host<F, R>(&self, hostname: &str, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut MemoryHost) -> Result<R>,
+    {
+        match self.hosts.borrow_mut().get_mut(hostname) {
+            Some(host) => f(host),
+            None => Err(PolyError::Resolve(String::from(hostname))),
+        }
+    }
+
+    /// Hand frame to a new segment on hostname, like its wormgate would spawn one for it
+    ///
+    /// The segment waits for a worm just like a real one and only keeps the worm once it
+    /// acknowledged the exact state we sent, which hosts running a segment never do.
+    fn deliver(&self, hostname: &str, frame: &Frame) -> Result<()> {
+        let mut payload = Vec::new();
+        frame.write_to(&mut payload, None)?;
+        self.with_host(hostname, |host| {
+            if host.segment.is_some() {
+                return Err(refused(hostname));
+            }
+            host.states.push_back(payload);
+            Ok(())
+        })?;
+
+        let worm = listen_for_worm(&self.transport(hostname));
+        let ack = self.with_host(hostname, |host| {
+            host.states.clear();
+            Ok(host.ack.take())
+        })?;
+        let worm = worm?;
+        match ack {
+            Some(Message::StateAck { ref state_hash, .. }) if *state_hash == frame.digest() => {
+                self.with_host(hostname, |host| {
+                    host.worms.push_back(worm);
+                    Ok(())
+                })
+            }
+            ack => Err(PolyError::Protocol(format!(
+                "{} did not acknowledge the state: {:?}",
+                hostname, ack
+            ))),
+        }
+    }
+
+    fn segment(&self, hostname: &str) -> Result<Rc<RefCell<Agent>>> {
+        self.with_host(hostname, |host| {
+            host.segment
+                .clone()
+                .ok_or_else(|| refused(hostname))
+        })
+    }
+}
+
+impl Default for MemoryNetwork {
+    fn default() -> MemoryNetwork {
+        MemoryNetwork::new()
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn hostname(&self) -> &str {
+        &self.hostname
+    }
+
+    fn run_id(&self) -> &str {
+        &self.network.run_id
+    }
+
+    fn now(&self) -> Instant {
+        self.network.start + self.network.elapsed()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        self.network.start_time + self.network.elapsed()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.network.advance(duration);
+    }
+
+    fn send_message(&self, host: &str, msg: &Message) -> Result<()> {
+        self.network.count_message();
+        self.network.with_host(host, |h| {
+            if h.segment.is_none() {
+                return Err(refused(host));
+            }
+            h.inbox.push_back(msg.clone());
+            Ok(())
+        })
+    }
+
+    fn request(&self, host: &str, msg: &Message) -> Result<Message> {
+        self.network.count_message();
+        let segment = self.network.segment(host)?;
+        // A segment that is busy cannot answer, just like a TCP segment that is not listening
+        let mut segment = segment.try_borrow_mut().map_err(|_| refused(host))?;
+        segment
+            .handle_message(&self.network.transport(host), msg.clone())
+            .ok_or_else(|| PolyError::Protocol(format!("{} did not reply", host)))
+    }
+
+    fn request_within(&self, host: &str, msg: &Message, _timeout: Duration) -> Result<Message> {
+        // Segments answer right away or not at all, so there is nothing to wait for
+        self.request(host, msg)
+    }
+
+    fn start_listening(&self) -> Result<()> {
+        // Messages are queued in the inbox of the host as long as a segment is attached
+        Ok(())
+    }
+
+    fn listen(
+        &self,
+        _deadline: Instant,
+        handler: &mut dyn FnMut(Message) -> Option<Message>,
+    ) -> Result<()> {
+        loop {
+            let msg = self.network
+                .with_host(&self.hostname, |host| Ok(host.inbox.pop_front()))?;
+            match msg {
+                // Replies are only delivered for requests, so they are dropped here
+                Some(msg) => {
+                    let _reply = handler(msg);
+                }
+                None => return Ok(()),
+            }
+        }
+    }
+
+    fn send_state(&self, host: &str, worm: &Worm) -> Result<()> {
+        self.network.count_message();
+        self.network
+            .deliver(host, &Frame::encode(FrameKind::State, worm)?)
+    }
+
+    fn send_start(&self, host: &str, start: &Message) -> Result<()> {
+        self.network.count_message();
+        self.network
+            .deliver(host, &wire::message_frame(self.run_id(), start)?)
+    }
+
+    fn accept_state(
+        &self,
+        _deadline: Instant,
+        handler: &mut dyn FnMut(Frame) -> Option<Message>,
+    ) -> Result<()> {
+        let payload = self.network.with_host(&self.hostname, |host| {
+            host.states
+                .pop_front()
+                .ok_or_else(|| PolyError::Protocol(String::from("No state transfer pending")))
+        })?;
+        let ack = handler(Frame::read_from(payload.as_slice(), None)?);
+        self.network.with_host(&self.hostname, |host| {
+            host.ack = ack;
+            Ok(())
+        })
+    }
+
+    fn upload_binary(
+        &self,
+        host: &str,
+        _wormgate_port: u16,
+        _signature: Option<&str>,
+    ) -> Result<()> {
+        self.network.count_message();
+        self.network.with_host(host, |h| {
+            h.uploads += 1;
+            Ok(())
+        })
+    }
+
+    fn fetch_observation_data(&self, _wormgate_port: u16) -> Result<HashMap<String, String>> {
+        self.network.with_host(&self.hostname, |host| {
+            let mut data = HashMap::new();
+            data.insert(self.hostname.clone(), host.observation.clone());
+            Ok(data)
+        })
+    }
+
+    fn post_observation_data(
+        &self,
+        _wormgate_port: u16,
+        data: &HashMap<String, String>,
+    ) -> Result<()> {
+        self.network.with_host(&self.hostname, |host| {
+            host.returned_data = Some(data.clone());
+            Ok(())
+        })
+    }
+}
+
+fn refused(hostname: &str) -> PolyError {
+    PolyError::Io(io::Error::new(
+        io::ErrorKind::ConnectionRefused,
+        format!("no segment listening on {}", hostname),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn network() -> MemoryNetwork {
+        let network = MemoryNetwork::new().with_run_id("run");
+        network.add_host("a", "observation from a");
+        network.add_host("b", "observation from b");
+        network
+    }
+
+    fn worm(run_id: &str) -> Worm {
+        Worm::new(run_id, "a", 2, 0, vec![String::from("a"), String::from("b")])
+    }
+
+    #[test]
+    fn acknowledged_transfer() {
+        let network = network();
+        let mut worm = worm("run");
+        worm.send_data_to_host(&network.transport("a"), "b").unwrap();
+        assert_eq!(worm.cur_num_segments, 2);
+        assert!(worm.has_segment("b"));
+
+        let arrived = network.take_worm("b").expect("No worm arrived");
+        assert_eq!(arrived.current_hostname, "b");
+        assert_eq!(arrived.hops, 1);
+        assert_eq!(network.pending_worms("b"), 0);
+    }
+
+    #[test]
+    fn unacknowledged_transfer_is_rolled_back() {
+        let network = network();
+        // The segment waiting on b belongs to another run and ignores the worm
+        let mut worm = worm("other");
+        let segments = worm.current_segments.clone();
+        assert!(worm.send_data_to_host(&network.transport("a"), "b").is_err());
+        assert_eq!(worm.cur_num_segments, 1);
+        assert_eq!(worm.current_segments, segments);
+        assert!(network.take_worm("b").is_none());
+    }
+}
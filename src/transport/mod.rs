@@ -0,0 +1,260 @@
+use nix;
+use nix::unistd::gethostname;
+
+use std::env;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::collections::HashMap;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use auth;
+use backoff::FailureTracker;
+use error::{PolyError, Result};
+use protocol::{Envelope, Message, TreeState, WormSegment};
+use wire::{Frame, FrameKind};
+use worm::Worm;
+
+pub mod memory;
+pub mod ports;
+pub mod tcp;
+
+pub use self::memory::{MemoryNetwork, MemoryTransport};
+pub use self::ports::PortRange;
+pub use self::tcp::TcpTransport;
+
+/// Everything a segment needs to talk to other segments and to its wormgate
+pub trait Transport {
+    /// Hostname of the host this transport sends from
+    fn hostname(&self) -> &str;
+
+    /// Run the segments talking over this transport belong to
+    fn run_id(&self) -> &str;
+
+    /// Current time, which is virtual for simulated transports
+    fn now(&self) -> Instant;
+
+    /// Current wall clock time, which is virtual for simulated transports
+    fn system_time(&self) -> SystemTime;
+
+    /// Wait for the given duration
+    fn sleep(&self, duration: Duration);
+
+    /// Send a message to the segment on host without waiting for an answer
+    fn send_message(&self, host: &str, msg: &Message) -> Result<()>;
+
+    /// Send a message to the segment on host and wait for its reply
+    fn request(&self, host: &str, msg: &Message) -> Result<Message>;
+
+    /// Send a message to the segment on host and wait up to timeout for its reply
+    fn request_within(&self, host: &str, msg: &Message, timeout: Duration) -> Result<Message>;
+
+    /// Start accepting messages in the background, so none are lost while the segment is busy
+    fn start_listening(&self) -> Result<()>;
+
+    /// Pass incoming messages to handler until the deadline has passed
+    ///
+    /// Messages that arrived since the last call are handled first, so a deadline in the
+    /// past handles those without waiting for more. The handler may return a reply,
+    /// which is sent back to the requesting segment.
+    fn listen(
+        &self,
+        deadline: Instant,
+        handler: &mut dyn FnMut(Message) -> Option<Message>,
+    ) -> Result<()>;
+
+    /// Transfer the worm state to the segment waiting on host
+    ///
+    /// Only succeeds once the segment acknowledged the exact state we sent.
+    fn send_state(&self, host: &str, worm: &Worm) -> Result<()>;
+
+    /// Send a start command to the segment waiting for a worm on host
+    fn send_start(&self, host: &str, start: &Message) -> Result<()>;
+
+    /// Wait for a state transfer or start command and pass its frame to handler
+    ///
+    /// Returns once the handler accepts a frame by returning the acknowledgement to
+    /// send back to the sender, or with an error once deadline passes.
+    fn accept_state(
+        &self,
+        deadline: Instant,
+        handler: &mut dyn FnMut(Frame) -> Option<Message>,
+    ) -> Result<()>;
+
+    /// Upload the program to the wormgate on host so a new segment is spawned there
+    ///
+    /// The upload carries signature, or the one shipped next to the program without it,
+    /// and fails unless the wormgate accepts it.
+    fn upload_binary(
+        &self,
+        host: &str,
+        wormgate_port: u16,
+        signature: Option<&str>,
+    ) -> Result<()>;
+
+    /// Fetch observation data from the wormgate on this host
+    fn fetch_observation_data(&self, wormgate_port: u16) -> Result<HashMap<String, String>>;
+
+    /// Post the gathered observation data to the wormgate on this host
+    fn post_observation_data(
+        &self,
+        wormgate_port: u16,
+        data: &HashMap<String, String>,
+    ) -> Result<()>;
+}
+
+/// Environment variable naming the host a segment runs on, instead of the real hostname
+///
+/// Set by wormgates standing in for several hosts on one machine.
+pub const HOSTNAME_VAR: &str = "POLY_HOSTNAME";
+
+/// Get the short hostname of the current host, unless POLY_HOSTNAME names another one
+pub fn local_hostname() -> Result<String> {
+    if let Some(hostname) = env::var(HOSTNAME_VAR).ok().filter(|h| !h.is_empty()) {
+        return Ok(hostname);
+    }
+    let mut buf = vec![0; 50];
+    let hostname = gethostname(&mut buf).map_err(|e| match e {
+        nix::Error::Sys(errno) => PolyError::Io(io::Error::from_raw_os_error(errno as i32)),
+        e => PolyError::Resolve(format!("unable to get local hostname: {}", e)),
+    })?;
+    let hostname = hostname
+        .to_str()
+        .map_err(|_| PolyError::Resolve(String::from("local hostname is not valid UTF-8")))?;
+    Ok(String::from(hostname.split('.').next().unwrap_or(hostname)))
+}
+
+/// Seconds since the unix epoch, used for deadlines carried between hosts
+pub fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Resolve hostname and port to the first matching socket address
+pub fn resolve(hostname: &str, port: u64) -> Result<SocketAddr> {
+    format!("{}:{}", hostname, port)
+        .to_socket_addrs()
+        .map_err(|e| PolyError::Resolve(format!("{}: {}", hostname, e)))?
+        .next()
+        .ok_or_else(|| PolyError::Resolve(format!("{}: no matching addresses", hostname)))
+}
+
+/// How long a run may live before its segments clean up after themselves
+pub const DEFAULT_TTL_SECS: u64 = 60 * 60;
+
+/// How many state transfers a worm may go through before it stops
+pub const DEFAULT_MAX_HOPS: usize = 256;
+
+/// How many segments are told about a new segment or the death of one
+pub const DEFAULT_GOSSIP_FANOUT: usize = 5;
+
+/// How long the operator waits for a run to acknowledge an abort, unless told otherwise
+pub const ABORT_WAIT: Duration = Duration::from_secs(30);
+
+/// Time a segment keeps to acknowledge an abort after hearing back from the ones it passed
+/// it on to
+pub const ABORT_REPLY_MARGIN: Duration = Duration::from_millis(500);
+
+/// How long a freshly spawned segment waits for its worm before giving up
+pub const WORM_WAIT: Duration = Duration::from_secs(10 * 60);
+
+/// Listen for either the start command or a worm from parent segment
+/// Update worm segment status after receiving it from parent
+///
+/// The sender gets an acknowledgement once we have the worm. Frames that are neither,
+/// or belong to a different run, are logged and ignored. Segments nobody sends a worm
+/// to within WORM_WAIT give up, so orphans of failed infections do not linger.
+pub fn listen_for_worm<T: Transport>(transport: &T) -> Result<Worm> {
+    let hostname = transport.hostname();
+    let deadline = transport.now() + WORM_WAIT;
+    loop {
+        let mut worm = None;
+        transport.accept_state(deadline, &mut |frame| {
+            worm = worm_from_frame(transport, &frame);
+            worm.as_ref().map(|_| Message::StateAck {
+                hostname: String::from(hostname),
+                state_hash: frame.digest(),
+            })
+        })?;
+        if let Some(worm) = worm {
+            return Ok(worm);
+        }
+    }
+}
+
+/// Turn a state transfer or start command into the worm of this segment
+fn worm_from_frame<T: Transport>(transport: &T, frame: &Frame) -> Option<Worm> {
+    let hostname = transport.hostname();
+    match frame.kind {
+        FrameKind::State => match frame.decode::<Worm>(FrameKind::State) {
+            Ok(ref worm) if worm.run_id != transport.run_id() => {
+                warn!("Ignoring worm from run {}", worm.run_id);
+            }
+            Ok(mut worm) => {
+                debug!("Deserialized worm data from stream");
+
+                // Update worm segment data by calling the method for converting segment status
+                worm.current_hostname = hostname.to_string();
+                worm.hops += 1;
+                worm.current_segments = worm.current_segments
+                    .iter()
+                    .map(|segment| segment.send_to(&WormSegment::new(TreeState::Child, hostname)))
+                    .collect();
+
+                return Some(worm);
+            }
+            Err(e) => warn!("Unable to deserialize worm data: {}", e),
+        },
+        FrameKind::Message => match frame.decode_envelope(transport.run_id()) {
+            Ok(envelope) => return worm_from_start(transport, envelope),
+            Err(e) => warn!("Unable to deserialize start command: {}", e),
+        },
+    }
+    None
+}
+
+/// Turn a start command into the worm of the initial segment of the run of the envelope
+fn worm_from_start<T: Transport>(transport: &T, envelope: Envelope) -> Option<Worm> {
+    let hostname = transport.hostname();
+    match envelope.message {
+        Message::Start {
+            wormgate_port,
+            mut hosts,
+            max_segments,
+            allowed_hosts,
+            ttl_secs,
+            max_hops,
+            scheduler,
+            placement,
+            wormgate_ports,
+            gossip_fanout,
+            retry,
+        } => {
+            info!("Got a start command - this is the initial segment");
+            if !hosts.iter().any(|h| h == hostname) {
+                hosts.insert(0, String::from(hostname));
+            }
+            let mut worm =
+                Worm::new(&envelope.run_id, hostname, max_segments, wormgate_port, hosts);
+            if !allowed_hosts.is_empty() {
+                worm.set_allowed_hosts(allowed_hosts);
+            }
+            let now = unix_time(transport.system_time());
+            worm.deadline = ttl_secs.map(|ttl| now.saturating_add(ttl));
+            worm.max_hops = max_hops;
+            worm.scheduler = scheduler;
+            worm.placement = placement;
+            worm.wormgate_ports = wormgate_ports;
+            worm.gossip_fanout = gossip_fanout.unwrap_or(DEFAULT_GOSSIP_FANOUT);
+            worm.failures = FailureTracker::with_policy(retry);
+            worm.binary_signature = env::current_exe()
+                .ok()
+                .and_then(|binary| auth::read_signature(&binary));
+            Some(worm)
+        }
+        msg => {
+            warn!("Ignoring {:?} while waiting for a worm", msg);
+            None
+        }
+    }
+}
@@ -0,0 +1,256 @@
+//! Ports segments listen on, derived from hostname and run id
+//!
+//! Ports are picked with 64 bit FNV-1a, which unlike the hasher in std gives the
+//! same result on every toolchain. The port range is split into pairs: the even port
+//! of a pair takes state transfers and the odd one gossip. The hash picks a pair,
+//! and when its ports are taken the segment probes the pairs following it. Senders
+//! connect to the ports in probe order and greet whatever answers, moving on when it
+//! belongs to another run or is not a segment at all.
+//!
+//! When the wormgate port is known, segments instead bind an ephemeral port and
+//! register it with their local wormgate, and peers look it up there before
+//! connecting. The hashed ports are only used when that fails.
+
+use reqwest;
+
+use std::env;
+use std::str::FromStr;
+
+use error::{PolyError, Result};
+
+/// Environment variable holding the port range, as `first-last`
+pub const PORT_RANGE_VAR: &str = "POLY_PORT_RANGE";
+
+/// Environment variable holding the port of the wormgates, used for port discovery
+pub const WORMGATE_PORT_VAR: &str = "POLY_WORMGATE_PORT";
+
+/// Wormgate path segments register their ports at, followed by the run id
+pub const SEGMENT_PORTS_PATH: &str = "/segment_ports/";
+
+/// Number of pairs tried before giving up on finding a free port
+pub const MAX_PROBES: u64 = 8;
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// Range of ports segments may listen on
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct PortRange {
+    first: u16,
+    last: u16,
+}
+
+/// Ports the segment of a run registered with its wormgate
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+pub struct SegmentPorts {
+    pub state: Option<u16>,
+    pub gossip: Option<u16>,
+}
+
+/// 64 bit FNV-1a hash of parts, with a separator so ("ab", "c") and ("a", "bc") differ
+pub fn fnv1a(parts: &[&[u8]]) -> u64 {
+    let mut hash = FNV_OFFSET_BASIS;
+    for (i, part) in parts.iter().enumerate() {
+        if i > 0 {
+            hash ^= 0xff;
+            hash = hash.wrapping_mul(FNV_PRIME);
+        }
+        for byte in part.iter() {
+            hash ^= u64::from(*byte);
+            hash = hash.wrapping_mul(FNV_PRIME);
+        }
+    }
+    hash
+}
+
+impl PortRange {
+    /// Create a range from first to last, both included
+    ///
+    /// The range has to hold at least one pair of ports outside the privileged ports.
+    pub fn new(first: u16, last: u16) -> Result<PortRange> {
+        if first < 1024 || last <= first {
+            return Err(PolyError::Config(format!(
+                "invalid port range {}-{}",
+                first, last
+            )));
+        }
+        Ok(PortRange { first, last })
+    }
+
+    /// Number of port pairs in the range
+    pub fn pairs(&self) -> u64 {
+        u64::from(self.last - self.first).div_ceil(2)
+    }
+
+    /// Port of hostname in the given run, skipping probe pairs that were taken
+    pub fn port(&self, hostname: &str, run_id: &str, state_transfer: bool, probe: u64) -> u16 {
+        let hash = fnv1a(&[hostname.as_bytes(), run_id.as_bytes()]);
+        let pair = hash.wrapping_add(probe) % self.pairs();
+        let offset = if state_transfer { 0 } else { 1 };
+        (u64::from(self.first) + pair * 2 + offset) as u16
+    }
+
+    /// Ports of hostname in the given run in the order they should be tried
+    pub fn probe(&self, hostname: &str, run_id: &str, state_transfer: bool) -> Vec<u16> {
+        (0..MAX_PROBES.min(self.pairs()))
+            .map(|probe| self.port(hostname, run_id, state_transfer, probe))
+            .collect()
+    }
+}
+
+impl Default for PortRange {
+    fn default() -> PortRange {
+        PortRange {
+            first: 1024,
+            last: 65535,
+        }
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = PolyError;
+
+    fn from_str(s: &str) -> Result<PortRange> {
+        let invalid = || PolyError::Config(format!("invalid port range {:?}", s));
+        let mut parts = s.trim().splitn(2, '-');
+        let first = parts.next().and_then(|p| p.trim().parse().ok());
+        let last = parts.next().and_then(|p| p.trim().parse().ok());
+        match (first, last) {
+            (Some(first), Some(last)) => PortRange::new(first, last),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// Read the port range from the environment, falling back to the default range
+pub fn port_range() -> Result<PortRange> {
+    match env::var(PORT_RANGE_VAR) {
+        Ok(range) => range.parse(),
+        Err(_) => Ok(PortRange::default()),
+    }
+}
+
+/// Read the wormgate port from the environment, if it is set
+pub fn wormgate_port() -> Option<u16> {
+    env::var(WORMGATE_PORT_VAR)
+        .ok()
+        .and_then(|port| port.trim().parse().ok())
+}
+
+impl SegmentPorts {
+    /// Registration of a single port
+    pub fn new(state_transfer: bool, port: u16) -> SegmentPorts {
+        if state_transfer {
+            SegmentPorts {
+                state: Some(port),
+                gossip: None,
+            }
+        } else {
+            SegmentPorts {
+                state: None,
+                gossip: Some(port),
+            }
+        }
+    }
+
+    /// Merge a later registration into this one
+    pub fn update(&mut self, other: &SegmentPorts) {
+        self.state = other.state.or(self.state);
+        self.gossip = other.gossip.or(self.gossip);
+    }
+
+    /// The registered port of the given kind
+    pub fn get(&self, state_transfer: bool) -> Option<u16> {
+        if state_transfer {
+            self.state
+        } else {
+            self.gossip
+        }
+    }
+}
+
+/// Register the port our segment of the run listens on with the wormgate on this host
+pub fn register_port(
+    wormgate_port: u16,
+    run_id: &str,
+    state_transfer: bool,
+    port: u16,
+) -> Result<()> {
+    let client = reqwest::Client::new();
+    let res = client
+        .post(&format!(
+            "http://localhost:{}{}{}",
+            wormgate_port, SEGMENT_PORTS_PATH, run_id
+        ))
+        .json(&SegmentPorts::new(state_transfer, port))
+        .send()?;
+    if !res.status().is_success() {
+        return Err(PolyError::Protocol(format!(
+            "Wormgate refused port registration: {}",
+            res.status()
+        )));
+    }
+    Ok(())
+}
+
+/// Look up the port the segment of the run on host registered with its wormgate
+pub fn lookup_port(
+    host: &str,
+    wormgate_port: u16,
+    run_id: &str,
+    state_transfer: bool,
+) -> Result<Option<u16>> {
+    let mut res = reqwest::get(&format!(
+        "http://{}:{}{}{}",
+        host, wormgate_port, SEGMENT_PORTS_PATH, run_id
+    ))?;
+    if !res.status().is_success() {
+        return Ok(None);
+    }
+    let ports: SegmentPorts = res.json()?;
+    Ok(ports.get(state_transfer))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fnv1a_vectors() {
+        assert_eq!(fnv1a(&[b""]), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a(&[b"a"]), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(fnv1a(&[b"foobar"]), 0x8594_4171_f739_67e8);
+        assert_ne!(fnv1a(&[b"ab", b"c"]), fnv1a(&[b"a", b"bc"]));
+    }
+
+    #[test]
+    fn stable_ports() {
+        let range = PortRange::default();
+        assert_eq!(range.port("compute-1-1", "default", true, 0), 16984);
+        assert_eq!(range.port("compute-1-1", "default", false, 0), 16985);
+        assert_eq!(range.port("compute-1-1", "default", true, 1), 16986);
+    }
+
+    #[test]
+    fn probe_stays_in_range() {
+        let range: PortRange = "20000-20009".parse().unwrap();
+        let state = range.probe("compute-1-1", "default", true);
+        assert_eq!(state, vec![20006, 20008, 20000, 20002, 20004]);
+        let gossip = range.probe("compute-1-1", "default", false);
+        assert!(gossip.iter().all(|port| *port > 20000 && *port <= 20009));
+
+        let range = PortRange::default();
+        for host in &["a", "b", "compute-9-9"] {
+            let ports = range.probe(host, "run", false);
+            assert_eq!(ports.len() as u64, MAX_PROBES);
+            assert!(ports.iter().all(|port| *port >= 1024 && port % 2 == 1));
+        }
+    }
+
+    #[test]
+    fn invalid_ranges() {
+        assert!("1000-2000".parse::<PortRange>().is_err());
+        assert!("3000-3000".parse::<PortRange>().is_err());
+        assert!("3000".parse::<PortRange>().is_err());
+    }
+}
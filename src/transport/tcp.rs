@@ -0,0 +1,623 @@
+use reqwest;
+use reqwest::header::Headers;
+
+use std::io::{self, Read};
+use std::fs::File;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use std::env;
+
+use auth::{self, ReplayGuard};
+use error::{PolyError, Result};
+use logging;
+use protocol::{self, Envelope, Message};
+use wire::{self, Frame, FrameKind};
+use worm::Worm;
+use super::{resolve, ports, unix_time, PortRange, Transport};
+
+/// How long to wait for a peer to send a message or reply, unless told otherwise
+pub const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often to check for a connection while waiting for a worm
+const ACCEPT_POLL: Duration = Duration::from_millis(50);
+
+/// Most connections read at once, connections beyond it are closed right away
+pub const MAX_READERS: usize = 32;
+
+/// A message read by the listener thread, with the connection to answer it on
+struct Incoming {
+    message: Message,
+    stream: TcpStream,
+}
+
+/// Transport talking to other segments over TCP and to wormgates over HTTP
+///
+/// Messages are accepted by a listener thread started once per segment, read on a
+/// thread per connection, and wait in a channel until the segment gets around to them.
+pub struct TcpTransport {
+    hostname: String,
+    run_id: String,
+    port_range: PortRange,
+    wormgate_port: Option<u16>,
+    wormgate_ports: HashMap<String, u16>,
+    cluster_key: Option<Vec<u8>>,
+    timeout: Duration,
+    rejected_frames: Arc<AtomicUsize>,
+    replay_guard: Arc<Mutex<ReplayGuard>>,
+    incoming: Mutex<Option<Receiver<Incoming>>>,
+}
+
+impl TcpTransport {
+    /// Create a transport for the segment running on hostname
+    pub fn new(hostname: &str) -> TcpTransport {
+        TcpTransport {
+            hostname: String::from(hostname),
+            run_id: String::from(protocol::DEFAULT_RUN_ID),
+            port_range: PortRange::default(),
+            wormgate_port: None,
+            wormgate_ports: HashMap::new(),
+            cluster_key: None,
+            timeout: READ_TIMEOUT,
+            rejected_frames: Arc::new(AtomicUsize::new(0)),
+            replay_guard: Arc::new(Mutex::new(ReplayGuard::new())),
+            incoming: Mutex::new(None),
+        }
+    }
+
+    /// Only talk to segments of the given run
+    pub fn with_run_id(mut self, run_id: &str) -> TcpTransport {
+        self.run_id = String::from(run_id);
+        self
+    }
+
+    /// Derive the ports of segments from the given range
+    pub fn with_port_range(mut self, port_range: PortRange) -> TcpTransport {
+        self.port_range = port_range;
+        self
+    }
+
+    /// Discover the ports of segments through the wormgates listening on wormgate_port
+    pub fn with_wormgate_port(mut self, wormgate_port: Option<u16>) -> TcpTransport {
+        self.wormgate_port = wormgate_port;
+        self
+    }
+
+    /// Look up ports on the hosts in wormgate_ports through the wormgate on that port
+    pub fn with_wormgate_ports(mut self, wormgate_ports: HashMap<String, u16>) -> TcpTransport {
+        self.wormgate_ports = wormgate_ports;
+        self
+    }
+
+    /// Authenticate every frame with the shared cluster key, rejecting frames without it
+    pub fn with_cluster_key(mut self, key: Option<Vec<u8>>) -> TcpTransport {
+        self.cluster_key = key;
+        self
+    }
+
+    /// Wait this long for the replies to our requests and state transfers
+    pub fn with_timeout(mut self, timeout: Duration) -> TcpTransport {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Number of incoming frames rejected because they were not authenticated, stale or replayed
+    pub fn rejected_frames(&self) -> usize {
+        self.rejected_frames.load(Ordering::SeqCst)
+    }
+
+    fn key(&self) -> Option<&[u8]> {
+        self.cluster_key.as_deref()
+    }
+
+    fn count_rejected<T>(&self, result: Result<T>) -> Result<T> {
+        count_rejected(&self.rejected_frames, result)
+    }
+
+    /// Read a frame from stream, counting it if it is rejected
+    fn read_frame(&self, stream: &TcpStream) -> Result<Frame> {
+        self.count_rejected(read_frame(stream, self.key(), &self.replay_guard))
+    }
+
+    /// Read a message of our run from stream, counting it if it is rejected
+    fn read_message(&self, stream: &TcpStream) -> Result<Message> {
+        self.read_frame(stream)?.decode_message(&self.run_id)
+    }
+
+    /// Greet whatever listens at the other end of stream, making sure it is a segment of our run
+    fn greet(&self, stream: &TcpStream) -> Result<()> {
+        stream.set_read_timeout(Some(self.timeout))?;
+        wire::write_message(stream, &self.run_id, &Message::Hello, self.key())?;
+        match self.read_message(stream)? {
+            Message::Hello => Ok(()),
+            Message::WrongRun(run_id) => Err(PolyError::WrongRun(run_id)),
+            reply => Err(PolyError::Protocol(format!("Unexpected greeting: {:?}", reply))),
+        }
+    }
+
+    /// Connect to addr and greet the segment there
+    fn connect_to(&self, addr: &SocketAddr) -> Result<TcpStream> {
+        let stream = TcpStream::connect_timeout(addr, Duration::from_secs(1))?;
+        self.greet(&stream)?;
+        Ok(stream)
+    }
+
+    /// Connect to the segment of our run on hostname
+    ///
+    /// Uses the port registered with the wormgate on hostname if there is one, and
+    /// otherwise probes the hashed ports in order, skipping ports held by other runs.
+    fn connect(&self, hostname: &str, state_transfer: bool) -> Result<TcpStream> {
+        let wormgate_port = self.wormgate_ports
+            .get(hostname)
+            .cloned()
+            .or(self.wormgate_port);
+        if let Some(wormgate_port) = wormgate_port {
+            match ports::lookup_port(hostname, wormgate_port, &self.run_id, state_transfer) {
+                Ok(Some(port)) => {
+                    let addr = resolve(hostname, u64::from(port))?;
+                    match self.connect_to(&addr) {
+                        Ok(stream) => return Ok(stream),
+                        Err(e) => warn!(
+                            "Registered port {} on {} is not answering ({}) - trying hashed ports",
+                            port, hostname, e
+                        ),
+                    }
+                }
+                Ok(None) => debug!("No port registered on {} - trying hashed ports", hostname),
+                Err(e) => warn!(
+                    "Unable to look up port on {} ({}) - trying hashed ports",
+                    hostname, e
+                ),
+            }
+        }
+
+        let mut probed = false;
+        for port in self.port_range
+            .probe(hostname, &self.run_id, state_transfer)
+        {
+            let addr = resolve(hostname, u64::from(port))?;
+            match self.connect_to(&addr) {
+                Ok(stream) => return Ok(stream),
+                Err(ref e) if is_foreign(e) => {
+                    debug!("Port {} on {} is not ours ({}) - trying the next", port, hostname, e)
+                }
+                Err(PolyError::Io(ref e)) if e.kind() == io::ErrorKind::ConnectionRefused => {}
+                Err(e) => return Err(e),
+            }
+            probed = true;
+        }
+        Err(if probed {
+            PolyError::Io(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!("no segment of run {} on {}", self.run_id, hostname),
+            ))
+        } else {
+            PolyError::Config(String::from("port range is empty"))
+        })
+    }
+
+    /// Send a frame to the state port of host and wait for the segment there to acknowledge it
+    fn transfer(&self, host: &str, frame: &Frame) -> Result<()> {
+        let stream = self.connect(host, true)?;
+        debug!("Sending data to: {:?}", stream.peer_addr());
+        frame.write_to(&stream, self.key())?;
+
+        stream.set_read_timeout(Some(self.timeout))?;
+        match self.read_message(&stream)? {
+            Message::StateAck {
+                ref hostname,
+                ref state_hash,
+            } if *state_hash == frame.digest() =>
+            {
+                debug!("{} acknowledged the state transfer", hostname);
+                Ok(())
+            }
+            Message::StateAck { hostname, .. } => Err(PolyError::Protocol(format!(
+                "{} acknowledged a different state",
+                hostname
+            ))),
+            reply => Err(PolyError::Protocol(format!(
+                "Unexpected reply from {}: {:?}",
+                host, reply
+            ))),
+        }
+    }
+
+    /// Bind an ephemeral port and register it with the wormgate on this host
+    fn bind_registered(&self, wormgate_port: u16, state_transfer: bool) -> Result<TcpListener> {
+        let listener = TcpListener::bind(format!("{}:0", self.hostname))?;
+        let port = listener.local_addr()?.port();
+        ports::register_port(wormgate_port, &self.run_id, state_transfer, port)?;
+        info!("Listening at {}:{} (registered with wormgate)", self.hostname, port);
+        Ok(listener)
+    }
+
+    /// Bind a port for our run on this host
+    ///
+    /// Registers an ephemeral port with the wormgate if we know its port, and otherwise
+    /// binds the first free hashed port.
+    fn bind(&self, state_transfer: bool) -> Result<TcpListener> {
+        if let Some(wormgate_port) = self.wormgate_port {
+            match self.bind_registered(wormgate_port, state_transfer) {
+                Ok(listener) => return Ok(listener),
+                Err(e) => warn!(
+                    "Unable to register port with wormgate ({}) - using hashed ports",
+                    e
+                ),
+            }
+        }
+
+        let mut last_error = None;
+        for port in self.port_range
+            .probe(&self.hostname, &self.run_id, state_transfer)
+        {
+            match TcpListener::bind(format!("{}:{}", self.hostname, port)) {
+                Ok(listener) => {
+                    info!("Listening at {}:{}", self.hostname, port);
+                    return Ok(listener);
+                }
+                Err(e) => {
+                    warn!("Unable to bind {}:{}: {}", self.hostname, port, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(match last_error {
+            Some(e) => PolyError::Io(e),
+            None => PolyError::Config(String::from("port range is empty")),
+        })
+    }
+
+}
+
+/// Determine if a greeting failed because something other than a segment of our run answered
+fn is_foreign(e: &PolyError) -> bool {
+    match *e {
+        PolyError::WrongRun(_) | PolyError::Version(_) | PolyError::Protocol(_) => true,
+        PolyError::Io(ref e) => e.kind() == io::ErrorKind::UnexpectedEof,
+        _ => false,
+    }
+}
+
+/// Read a frame, refusing stale and replayed frames when frames are authenticated
+fn read_frame(stream: &TcpStream, key: Option<&[u8]>, guard: &Mutex<ReplayGuard>) -> Result<Frame> {
+    let frame = Frame::read_from(stream, key)?;
+    if key.is_some() {
+        let now = unix_time(SystemTime::now());
+        let mut guard = guard.lock().expect("Replay guard poisoned");
+        guard.check(frame.sent_at, frame.nonce, now)?;
+    }
+    Ok(frame)
+}
+
+/// Answer the greeting opening a connection, telling segments of other runs they are wrong
+fn answer_greeting(
+    stream: &TcpStream,
+    run_id: &str,
+    key: Option<&[u8]>,
+    guard: &Mutex<ReplayGuard>,
+) -> Result<()> {
+    match read_frame(stream, key, guard)?.decode_envelope(run_id) {
+        Ok(Envelope {
+            message: Message::Hello,
+            ..
+        }) => wire::write_message(stream, run_id, &Message::Hello, key),
+        Ok(envelope) => Err(PolyError::Protocol(format!(
+            "Expected a greeting, got {:?}",
+            envelope.message
+        ))),
+        Err(PolyError::WrongRun(other)) => {
+            let answer = Message::WrongRun(String::from(run_id));
+            wire::write_message(stream, &other, &answer, key)?;
+            Err(PolyError::WrongRun(other))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Count frames rejected by authentication as they pass by
+fn count_rejected<T>(rejected_frames: &AtomicUsize, result: Result<T>) -> Result<T> {
+    if let Err(PolyError::Auth(ref msg)) = result {
+        let rejected = rejected_frames.fetch_add(1, Ordering::SeqCst) + 1;
+        warn!("Rejected frame ({} so far): {}", rejected, msg);
+    }
+    result
+}
+
+/// Read messages from every connection to listener and pass them on to the segment
+///
+/// Every connection is read on a thread of its own, so a slow peer holds up nobody
+/// else. At most MAX_READERS are read at once, so a flood of connections cannot
+/// start threads without end. Stops once the segment has dropped its end of the channel.
+fn accept_messages(listener: TcpListener, connections: Connections) {
+    let context = logging::context();
+    for conn in listener.incoming() {
+        if connections.stopped.load(Ordering::SeqCst) {
+            return;
+        }
+        let stream = match conn {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("Unable to accept connection: {:?}", e);
+                continue;
+            }
+        };
+        if connections.readers.fetch_add(1, Ordering::SeqCst) >= MAX_READERS {
+            connections.readers.fetch_sub(1, Ordering::SeqCst);
+            warn!(
+                "Already reading {} connections - closing the one from {:?}",
+                MAX_READERS,
+                stream.peer_addr()
+            );
+            continue;
+        }
+        let connections = connections.clone();
+        let context = context.clone();
+        thread::spawn(move || {
+            logging::set_context(context);
+            connections.read_message(stream);
+            connections.readers.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+}
+
+/// What the threads reading connections for the listener share
+#[derive(Clone)]
+struct Connections {
+    run_id: String,
+    key: Option<Vec<u8>>,
+    rejected_frames: Arc<AtomicUsize>,
+    replay_guard: Arc<Mutex<ReplayGuard>>,
+    sender: Sender<Incoming>,
+    stopped: Arc<AtomicBool>,
+    readers: Arc<AtomicUsize>, // Connections being read right now
+}
+
+impl Connections {
+    /// Answer the greeting on stream and pass the message following it on to the segment
+    fn read_message(&self, stream: TcpStream) {
+        if let Err(e) = stream.set_read_timeout(Some(READ_TIMEOUT)) {
+            warn!("Unable to set read timeout: {}", e);
+        }
+        let key = self.key.as_deref();
+        let greeting = answer_greeting(&stream, &self.run_id, key, &self.replay_guard);
+        if let Err(e) = count_rejected(&self.rejected_frames, greeting) {
+            warn!("Refusing connection: {}", e);
+            return;
+        }
+        let frame = count_rejected(
+            &self.rejected_frames,
+            read_frame(&stream, key, &self.replay_guard),
+        );
+        match frame.and_then(|frame| frame.decode_message(&self.run_id)) {
+            Ok(message) => {
+                if self.sender.send(Incoming { message, stream }).is_err() {
+                    self.stopped.store(true, Ordering::SeqCst);
+                }
+            }
+            Err(e) => warn!("Error handling message: {}", e),
+        }
+    }
+}
+
+impl Transport for TcpTransport {
+    fn hostname(&self) -> &str {
+        &self.hostname
+    }
+
+    fn run_id(&self) -> &str {
+        &self.run_id
+    }
+
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+
+    fn send_message(&self, host: &str, msg: &Message) -> Result<()> {
+        let stream = self.connect(host, false)?;
+        wire::write_message(&stream, &self.run_id, msg, self.key())
+    }
+
+    fn request(&self, host: &str, msg: &Message) -> Result<Message> {
+        self.request_within(host, msg, self.timeout)
+    }
+
+    fn request_within(&self, host: &str, msg: &Message, timeout: Duration) -> Result<Message> {
+        let stream = self.connect(host, false)?;
+        // A peer busy asking us something in return must not block us forever
+        stream.set_read_timeout(Some(timeout.max(Duration::from_millis(1))))?;
+        wire::write_message(&stream, &self.run_id, msg, self.key())?;
+        self.read_message(&stream)
+    }
+
+    fn start_listening(&self) -> Result<()> {
+        let mut incoming = self.incoming.lock().expect("Listener channel poisoned");
+        if incoming.is_some() {
+            return Ok(());
+        }
+
+        let listener = self.bind(false)?;
+        let (sender, receiver) = mpsc::channel();
+        let connections = Connections {
+            run_id: self.run_id.clone(),
+            key: self.cluster_key.clone(),
+            rejected_frames: self.rejected_frames.clone(),
+            replay_guard: self.replay_guard.clone(),
+            sender,
+            stopped: Arc::new(AtomicBool::new(false)),
+            readers: Arc::new(AtomicUsize::new(0)),
+        };
+        let context = logging::context();
+        thread::spawn(move || {
+            logging::set_context(context);
+            accept_messages(listener, connections)
+        });
+        *incoming = Some(receiver);
+        Ok(())
+    }
+
+    fn listen(
+        &self,
+        deadline: Instant,
+        handler: &mut dyn FnMut(Message) -> Option<Message>,
+    ) -> Result<()> {
+        self.start_listening()?;
+        let incoming = self.incoming.lock().expect("Listener channel poisoned");
+        let receiver = match *incoming {
+            Some(ref receiver) => receiver,
+            None => return Err(PolyError::Protocol(String::from("Listener is not running"))),
+        };
+
+        loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            match receiver.recv_timeout(timeout) {
+                Ok(Incoming { message, stream }) => {
+                    debug!("We got a message!");
+                    if let Some(reply) = handler(message) {
+                        let sent = wire::write_message(&stream, &self.run_id, &reply, self.key());
+                        // Senders of one way messages such as a passed on abort hang up early
+                        if let Err(e) = sent {
+                            debug!("Unable to reply: {}", e);
+                        }
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => return Ok(()),
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(PolyError::Protocol(String::from("Listener thread stopped")))
+                }
+            }
+        }
+    }
+
+    fn send_state(&self, host: &str, worm: &Worm) -> Result<()> {
+        self.transfer(host, &Frame::encode(FrameKind::State, worm)?)
+    }
+
+    fn send_start(&self, host: &str, start: &Message) -> Result<()> {
+        self.transfer(host, &wire::message_frame(&self.run_id, start)?)
+    }
+
+    fn accept_state(
+        &self,
+        deadline: Instant,
+        handler: &mut dyn FnMut(Frame) -> Option<Message>,
+    ) -> Result<()> {
+        let listener = self.bind(true)?;
+        listener.set_nonblocking(true)?;
+
+        /* Accept TCP connections until one of them carries a frame the handler accepts */
+        loop {
+            let (stream, addr) = match listener.accept() {
+                Ok(accepted) => accepted,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    if Instant::now() >= deadline {
+                        return Err(PolyError::Io(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            "no worm arrived in time",
+                        )));
+                    }
+                    thread::sleep(ACCEPT_POLL);
+                    continue;
+                }
+                Err(e) => return Err(PolyError::Io(e)),
+            };
+            debug!("Got some data from {:?}", addr);
+            stream.set_nonblocking(false)?;
+            stream.set_read_timeout(Some(READ_TIMEOUT))?;
+            let greeting = answer_greeting(&stream, &self.run_id, self.key(), &self.replay_guard);
+            if let Err(e) = self.count_rejected(greeting) {
+                warn!("Refusing connection from {:?}: {}", addr, e);
+                continue;
+            }
+            let frame = match self.read_frame(&stream) {
+                Ok(frame) => frame,
+                Err(e) => {
+                    warn!("Ignoring connection from {:?}: {}", addr, e);
+                    continue;
+                }
+            };
+            if let Some(ack) = handler(frame) {
+                if let Err(e) = wire::write_message(&stream, &self.run_id, &ack, self.key()) {
+                    warn!("Unable to acknowledge state from {:?}: {}", addr, e);
+                }
+                return Ok(());
+            }
+        }
+    }
+
+    fn upload_binary(
+        &self,
+        host: &str,
+        wormgate_port: u16,
+        signature: Option<&str>,
+    ) -> Result<()> {
+        let client = reqwest::Client::new();
+        let mut buf = Vec::with_capacity(100);
+        let binary_name = env::current_exe()?;
+        let mut f = File::open(&binary_name)?;
+
+        // Read binary file into buffer and post it to wormgate
+        let _n = f.read_to_end(&mut buf)?;
+
+        // Attach digest and signature so the wormgate can check this is an approved build
+        let mut headers = Headers::new();
+        headers.set_raw(protocol::RUN_ID_HEADER, self.run_id.clone());
+        headers.set_raw(auth::DIGEST_HEADER, auth::to_hex(&auth::digest(&buf)));
+        match signature
+            .map(String::from)
+            .or_else(|| auth::read_signature(&binary_name))
+        {
+            Some(signature) => headers.set_raw(auth::SIGNATURE_HEADER, signature),
+            None => warn!("No signature found for {:?}", binary_name),
+        }
+
+        let res = client
+            .post(&format!("http://{}:{}/worm_entrance", host, wormgate_port))
+            .headers(headers)
+            .body(buf)
+            .send()?
+            .error_for_status()?;
+        debug!("Post result: {:?}", res);
+
+        // Give wormgate time to spawn the segment before the state arrives
+        thread::sleep(Duration::from_millis(100));
+        Ok(())
+    }
+
+    fn fetch_observation_data(&self, wormgate_port: u16) -> Result<HashMap<String, String>> {
+        Ok(reqwest::get(&format!(
+            "http://localhost:{}/observation_data",
+            wormgate_port
+        ))?
+            .json()?)
+    }
+
+    fn post_observation_data(
+        &self,
+        wormgate_port: u16,
+        data: &HashMap<String, String>,
+    ) -> Result<()> {
+        let client = reqwest::Client::new();
+        let res = client
+            .post(&format!(
+                "http://localhost:{}/observation_data",
+                wormgate_port
+            ))
+            .json(data)
+            .send()?;
+        debug!("Uploaded data to wormgate: {:?}", res);
+        Ok(())
+    }
+}
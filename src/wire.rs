@@ -0,0 +1,328 @@
+//! Framing used for everything segments send to each other over a stream
+//!
+//! Every frame starts with a 27 byte header: the magic bytes `POLY`, the protocol
+//! version, the kind of payload, flags, the unix time the frame was made and a random
+//! nonce as big endian u64s, and the payload length as a big endian u32. The payload
+//! itself is JSON. Authenticated frames are followed by an HMAC tag over header and
+//! payload, so receivers can trust time and nonce to refuse stale and replayed frames.
+//! Messages are wrapped in an envelope naming their run.
+//!
+//! Every connection starts with a Hello from the connecting segment, which the other
+//! end answers with Hello when it belongs to the same run and with WrongRun otherwise.
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use rand;
+use serde_json;
+
+use std::io::{Read, Write};
+use std::time::SystemTime;
+
+use auth::{self, TAG_LEN};
+use error::{PolyError, Result};
+use protocol::{Envelope, Message};
+use transport::unix_time;
+use worm::Worm;
+
+/// Magic bytes at the start of every frame
+pub const MAGIC: &[u8; 4] = b"POLY";
+
+/// Version of the wire protocol spoken by this build
+pub const PROTOCOL_VERSION: u8 = 5;
+
+/// Size of the frame header in bytes
+pub const HEADER_LEN: usize = 27;
+
+/// Flag set on frames followed by an authentication tag
+const FLAG_AUTHENTICATED: u8 = 1;
+
+/// Largest payload we are willing to read
+pub const MAX_PAYLOAD_LEN: usize = 16 * 1024 * 1024;
+
+/// What the payload of a frame contains
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum FrameKind {
+    /// Worm state transferred to a new segment
+    State,
+    /// A Message between segments
+    Message,
+}
+
+/// A decoded frame
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub kind: FrameKind,
+    pub sent_at: u64, // Seconds since the unix epoch when the frame was made
+    pub nonce: u64,
+    pub payload: Vec<u8>,
+}
+
+impl FrameKind {
+    fn to_byte(self) -> u8 {
+        match self {
+            FrameKind::State => 1,
+            FrameKind::Message => 2,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Result<FrameKind> {
+        match byte {
+            1 => Ok(FrameKind::State),
+            2 => Ok(FrameKind::Message),
+            _ => Err(PolyError::Protocol(format!("Unknown frame kind {}", byte))),
+        }
+    }
+}
+
+impl Frame {
+    /// Serialize value into a new frame of the given kind
+    pub fn encode<T: Serialize>(kind: FrameKind, value: &T) -> Result<Frame> {
+        Ok(Frame {
+            kind,
+            sent_at: unix_time(SystemTime::now()),
+            nonce: rand::random(),
+            payload: serde_json::to_vec(value)?,
+        })
+    }
+
+    /// Deserialize the payload, making sure the frame is of the expected kind
+    pub fn decode<T: DeserializeOwned>(&self, kind: FrameKind) -> Result<T> {
+        if self.kind != kind {
+            return Err(PolyError::Protocol(format!(
+                "Expected a {:?} frame, got {:?}",
+                kind, self.kind
+            )));
+        }
+        Ok(serde_json::from_slice(&self.payload)?)
+    }
+
+    /// Deserialize a message, rejecting messages that belong to a different run
+    pub fn decode_message(&self, run_id: &str) -> Result<Message> {
+        self.decode_envelope(run_id).map(|envelope| envelope.message)
+    }
+
+    /// Deserialize a message along with its envelope, rejecting other runs
+    pub fn decode_envelope(&self, run_id: &str) -> Result<Envelope> {
+        let envelope: Envelope = self.decode(FrameKind::Message)?;
+        if envelope.run_id != run_id {
+            return Err(PolyError::WrongRun(envelope.run_id));
+        }
+        Ok(envelope)
+    }
+
+    /// Hex encoded SHA-256 digest of the payload, acknowledged by the receiving segment
+    pub fn digest(&self) -> String {
+        auth::to_hex(&auth::digest(&self.payload))
+    }
+
+    /// Write the frame with its header, authenticated if we have a key
+    pub fn write_to<W: Write>(&self, mut writer: W, key: Option<&[u8]>) -> Result<()> {
+        if self.payload.len() > MAX_PAYLOAD_LEN {
+            return Err(PolyError::Protocol(format!(
+                "Payload of {} bytes is too large",
+                self.payload.len()
+            )));
+        }
+
+        let mut header = [0; HEADER_LEN];
+        header[..4].copy_from_slice(MAGIC);
+        header[4] = PROTOCOL_VERSION;
+        header[5] = self.kind.to_byte();
+        header[6] = if key.is_some() { FLAG_AUTHENTICATED } else { 0 };
+        header[7..15].copy_from_slice(&self.sent_at.to_be_bytes());
+        header[15..23].copy_from_slice(&self.nonce.to_be_bytes());
+        header[23..].copy_from_slice(&(self.payload.len() as u32).to_be_bytes());
+        writer.write_all(&header)?;
+        writer.write_all(&self.payload)?;
+        if let Some(key) = key {
+            writer.write_all(&auth::sign(key, &[&header, &self.payload]))?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Read a frame, rejecting anything that does not speak our protocol version
+    ///
+    /// With a key, frames without a valid authentication tag are rejected as well. Without
+    /// one, authenticated frames are rejected, since we are unable to check them.
+    pub fn read_from<R: Read>(mut reader: R, key: Option<&[u8]>) -> Result<Frame> {
+        let mut header = [0; HEADER_LEN];
+        reader.read_exact(&mut header)?;
+        if &header[..4] != MAGIC {
+            return Err(PolyError::Protocol(String::from(
+                "Missing magic bytes, peer is not a worm segment",
+            )));
+        }
+        if header[4] != PROTOCOL_VERSION {
+            return Err(PolyError::Version(header[4]));
+        }
+        let kind = FrameKind::from_byte(header[5])?;
+        let authenticated = header[6] & FLAG_AUTHENTICATED != 0;
+
+        let mut sent_at = [0; 8];
+        sent_at.copy_from_slice(&header[7..15]);
+        let mut nonce = [0; 8];
+        nonce.copy_from_slice(&header[15..23]);
+        let mut len = [0; 4];
+        len.copy_from_slice(&header[23..]);
+        let len = u32::from_be_bytes(len) as usize;
+        if len > MAX_PAYLOAD_LEN {
+            return Err(PolyError::Protocol(format!(
+                "Payload of {} bytes is too large",
+                len
+            )));
+        }
+
+        let mut payload = vec![0; len];
+        reader.read_exact(&mut payload)?;
+
+        let mut tag = [0; TAG_LEN];
+        if authenticated {
+            reader.read_exact(&mut tag)?;
+        }
+        match key {
+            Some(key) if authenticated => auth::verify(key, &[&header, &payload], &tag)?,
+            Some(_) => return Err(PolyError::Auth(String::from("Frame is not authenticated"))),
+            None if authenticated => {
+                return Err(PolyError::Auth(String::from(
+                    "Frame is authenticated, but we have no cluster key to check it",
+                )))
+            }
+            None => {}
+        }
+        Ok(Frame {
+            kind,
+            sent_at: u64::from_be_bytes(sent_at),
+            nonce: u64::from_be_bytes(nonce),
+            payload,
+        })
+    }
+}
+
+/// Wrap a message for the given run in a frame
+pub fn message_frame(run_id: &str, msg: &Message) -> Result<Frame> {
+    let envelope = Envelope {
+        run_id: String::from(run_id),
+        message: msg.clone(),
+    };
+    Frame::encode(FrameKind::Message, &envelope)
+}
+
+/// Write a message for the given run as a single frame
+pub fn write_message<W: Write>(
+    writer: W,
+    run_id: &str,
+    msg: &Message,
+    key: Option<&[u8]>,
+) -> Result<()> {
+    message_frame(run_id, msg)?.write_to(writer, key)
+}
+
+/// Read a single message frame for the given run
+pub fn read_message<R: Read>(reader: R, run_id: &str, key: Option<&[u8]>) -> Result<Message> {
+    Frame::read_from(reader, key)?.decode_message(run_id)
+}
+
+/// Write the worm state as a single frame
+pub fn write_state<W: Write>(writer: W, worm: &Worm, key: Option<&[u8]>) -> Result<()> {
+    Frame::encode(FrameKind::State, worm)?.write_to(writer, key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::ErrorKind;
+
+    const KEY: &[u8] = b"cluster key";
+
+    fn message_bytes(key: Option<&[u8]>) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write_message(&mut bytes, "run", &Message::WantData(String::from("a")), key).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn round_trip() {
+        for key in &[None, Some(KEY)] {
+            let frame = message_frame("run", &Message::WantData(String::from("a"))).unwrap();
+            let mut bytes = Vec::new();
+            frame.write_to(&mut bytes, *key).unwrap();
+            let read = Frame::read_from(bytes.as_slice(), *key).unwrap();
+            assert_eq!(read, frame);
+            match read.decode_message("run").unwrap() {
+                Message::WantData(ref host) if host == "a" => {}
+                msg => panic!("Decoded {:?}", msg),
+            }
+        }
+    }
+
+    #[test]
+    fn bad_magic() {
+        let mut bytes = message_bytes(None);
+        bytes[0] = b'X';
+        match Frame::read_from(bytes.as_slice(), None) {
+            Err(PolyError::Protocol(_)) => {}
+            result => panic!("Read {:?}", result),
+        }
+    }
+
+    #[test]
+    fn version_mismatch() {
+        let mut bytes = message_bytes(None);
+        bytes[4] = PROTOCOL_VERSION + 1;
+        match Frame::read_from(bytes.as_slice(), None) {
+            Err(PolyError::Version(version)) => assert_eq!(version, PROTOCOL_VERSION + 1),
+            result => panic!("Read {:?}", result),
+        }
+    }
+
+    #[test]
+    fn oversized_length() {
+        let mut bytes = message_bytes(None);
+        let len = (MAX_PAYLOAD_LEN as u32 + 1).to_be_bytes();
+        bytes[HEADER_LEN - 4..HEADER_LEN].copy_from_slice(&len);
+        match Frame::read_from(bytes.as_slice(), None) {
+            Err(PolyError::Protocol(_)) => {}
+            result => panic!("Read {:?}", result),
+        }
+    }
+
+    #[test]
+    fn truncated_payload() {
+        let bytes = message_bytes(None);
+        match Frame::read_from(&bytes[..bytes.len() - 1], None) {
+            Err(PolyError::Io(ref e)) if e.kind() == ErrorKind::UnexpectedEof => {}
+            result => panic!("Read {:?}", result),
+        }
+    }
+
+    #[test]
+    fn tampered_tag() {
+        let mut bytes = message_bytes(Some(KEY));
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        match Frame::read_from(bytes.as_slice(), Some(KEY)) {
+            Err(PolyError::Auth(_)) => {}
+            result => panic!("Read {:?}", result),
+        }
+    }
+
+    #[test]
+    fn missing_tag_with_key() {
+        let bytes = message_bytes(None);
+        match Frame::read_from(bytes.as_slice(), Some(KEY)) {
+            Err(PolyError::Auth(_)) => {}
+            result => panic!("Read {:?}", result),
+        }
+    }
+
+    #[test]
+    fn tag_without_key() {
+        let bytes = message_bytes(Some(KEY));
+        match Frame::read_from(bytes.as_slice(), None) {
+            Err(PolyError::Auth(_)) => {}
+            result => panic!("Read {:?}", result),
+        }
+    }
+}
@@ -0,0 +1,536 @@
+use std::collections::HashMap;
+use std::vec::Vec;
+use std::time::{Duration, Instant};
+
+use backoff::FailureTracker;
+use error::{PolyError, Result};
+use logging::Context;
+use placement::PlacementKind;
+use protocol::{AbortAck, Message, TreeState, WormSegment};
+use scheduler::{Event, SchedulerKind};
+use transport::{unix_time, Transport, ABORT_REPLY_MARGIN, DEFAULT_GOSSIP_FANOUT};
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct Worm {
+    pub run_id: String,
+    pub initial_hostname: String,
+    pub current_hostname: String, // Modify after sending
+    pub max_num_segments: usize,
+    pub cur_num_segments: usize,                   // Modify before sending
+    pub observation_data: HashMap<String, String>, // Modify with gossiping and after getting data
+    pub current_segments: Vec<WormSegment>, // Modify before sending and after sending (change state)
+    pub hosts_to_ovserve: Vec<String>,
+    pub allowed_hosts: Vec<String>, // Never contact hosts outside this list
+    pub wormgate_port: u16,
+    pub wormgate_ports: HashMap<String, u16>, // Hosts with a wormgate on a different port
+    pub gossip_fanout: usize, // Segments told about a new segment or our death
+    pub deadline: Option<u64>, // Seconds since the unix epoch after which the run stops
+    pub max_hops: Option<usize>,
+    pub hops: usize, // State transfers this worm has gone through
+    pub scheduler: SchedulerKind,
+    pub placement: PlacementKind,
+    #[serde(default)]
+    pub binary_signature: Option<String>, // Signature of our binary, sent along with uploads
+    #[serde(skip)]
+    pub aborted: bool, // Set when an operator aborts the run, never sent along
+    #[serde(skip)]
+    pub events: Vec<Event>, // What happened since the main loop last looked
+    pub failures: FailureTracker, // Hosts this segment was unable to infect
+}
+
+impl Worm {
+    /// Create a new worm with max number of segments and a list of hosts
+    ///
+    /// Should only be used the very first time a worm is created,
+    /// and the rest should simply be sent. Only the hosts to observe are allowed
+    /// to be contacted until a different allowlist is set.
+    pub fn new(
+        run_id: &str,
+        hostname: &str,
+        max_segments: usize,
+        worm_port: u16,
+        hosts: Vec<String>,
+    ) -> Worm {
+        Worm {
+            run_id: String::from(run_id),
+            allowed_hosts: hosts.clone(),
+            initial_hostname: String::from(hostname),
+            current_hostname: String::from(hostname),
+            max_num_segments: max_segments,
+            cur_num_segments: 1,
+            observation_data: HashMap::new(),
+            current_segments: vec![WormSegment::new(TreeState::This, hostname)],
+            hosts_to_ovserve: hosts,
+            wormgate_port: worm_port,
+            wormgate_ports: HashMap::new(),
+            gossip_fanout: DEFAULT_GOSSIP_FANOUT,
+            deadline: None,
+            max_hops: None,
+            hops: 0,
+            scheduler: SchedulerKind::default(),
+            placement: PlacementKind::default(),
+            binary_signature: None,
+            aborted: false,
+            events: Vec::new(),
+            failures: FailureTracker::new(),
+        }
+    }
+
+    /// Port of the wormgate on host
+    pub fn wormgate_port_of(&self, host: &str) -> u16 {
+        self.wormgate_ports
+            .get(host)
+            .cloned()
+            .unwrap_or(self.wormgate_port)
+    }
+
+    /// Role of this segment in the run, as it shows up in the log
+    pub fn role(&self) -> &'static str {
+        if self.current_hostname == self.initial_hostname {
+            "initial"
+        } else {
+            "child"
+        }
+    }
+
+    /// Context the segment carrying this worm logs with
+    pub fn log_context(&self) -> Context {
+        Context {
+            run_id: self.run_id.clone(),
+            hostname: self.current_hostname.clone(),
+            role: String::from(self.role()),
+            wormgate_port: Some(self.wormgate_port_of(&self.current_hostname)),
+        }
+    }
+
+    /// Get data from wormgate on current host
+    pub fn get_data<T: Transport>(&mut self, transport: &T) -> Result<()> {
+        let port = self.wormgate_port_of(&self.current_hostname);
+        let map = transport.fetch_observation_data(port)?;
+
+        for (k, v) in &map {
+            // Strip away port number from data
+            let host = k.split(':').next().unwrap_or(k);
+            self.observation_data
+                .insert(host.to_string(), v.to_string());
+        }
+        Ok(())
+    }
+
+    /// Restrict the hosts this run may contact, always including the initial host
+    pub fn set_allowed_hosts(&mut self, mut hosts: Vec<String>) {
+        if !hosts.contains(&self.initial_hostname) {
+            hosts.push(self.initial_hostname.clone());
+        }
+        self.allowed_hosts = hosts;
+    }
+
+    /// Determine if host is on the allowlist of this run
+    pub fn is_allowed(&self, host: &str) -> bool {
+        self.allowed_hosts.iter().any(|h| h == host)
+    }
+
+    /// Refuse and log any attempt to contact a host outside the allowlist
+    fn check_allowed(&self, host: &str) -> Result<()> {
+        if self.is_allowed(host) {
+            Ok(())
+        } else {
+            warn!("Refusing to contact {} - it is not on the allowlist", host);
+            Err(PolyError::Forbidden(String::from(host)))
+        }
+    }
+
+    /// Send a message to the segment on an allowed host
+    fn send_message<T: Transport>(&self, transport: &T, host: &str, msg: &Message) -> Result<()> {
+        self.check_allowed(host)?;
+        transport.send_message(host, msg)
+    }
+
+    /// Determine if the run has passed its deadline or gone through too many hops
+    pub fn is_expired<T: Transport>(&self, transport: &T) -> bool {
+        let late = self.deadline
+            .is_some_and(|deadline| unix_time(transport.system_time()) >= deadline);
+        let too_far = self.max_hops.is_some_and(|max_hops| self.hops >= max_hops);
+        late || too_far
+    }
+
+    /// Determine if the worm should infect a new host
+    pub fn should_infect(&self) -> bool {
+        self.cur_num_segments < self.max_num_segments
+    }
+
+    /// Perform actions based on the message type received
+    ///
+    /// Can either receive a message about a new segment or someone wants data from
+    /// the specific host. Returns the reply to send back, if any.
+    pub fn handle_message<T: Transport>(
+        &mut self,
+        transport: &T,
+        message: Message,
+    ) -> Option<Message> {
+        match message {
+            Message::NewSegment(segment) => {
+                debug!("Got message regarding a new segment: {:?}", segment);
+                if !self.current_segments.contains(&segment) {
+                    self.events.push(Event::NewSegment(segment.hostname.clone()));
+                    self.current_segments.push(segment);
+                    self.cur_num_segments += 1;
+                }
+            }
+            Message::WantData(hostname) => {
+                debug!(
+                    "Got message about someone that wanted data: {:?}",
+                    hostname
+                );
+                return Some(Message::Observation(
+                    self.observation_data.get(&hostname).cloned(),
+                ));
+            }
+            Message::SuicideNote(segment) => {
+                debug!("Got a suicide note from {:?}", segment);
+                self.forget_segment(&segment.hostname);
+            }
+            Message::GatheringCompleted => {
+                info!("Got message that we are completed!");
+                self.cur_num_segments = self.max_num_segments;
+                debug!("Setting current number of segments such that we should die");
+            }
+            Message::Observation(_) => {
+                debug!("Got an observation nobody asked for");
+            }
+            Message::PushData(data) => {
+                debug!("Got {} observations pushed to us", data.len());
+                for (hostname, observation) in data {
+                    if !self.observation_data.contains_key(&hostname) {
+                        self.events.push(Event::DataArrived(hostname.clone()));
+                        self.observation_data.insert(hostname, observation);
+                    }
+                }
+            }
+            Message::Start { .. } => {
+                warn!("Got a start command while already running - ignoring it");
+            }
+            Message::Abort {
+                run_id,
+                seen,
+                wait_ms,
+            } => {
+                if run_id != self.run_id {
+                    warn!("Ignoring abort for run {} - this is run {}", run_id, self.run_id);
+                    return None;
+                }
+                let wait = Duration::from_millis(wait_ms);
+                return Some(Message::AbortAck(self.abort(transport, seen, wait)));
+            }
+            Message::AbortAck(_) => {
+                debug!("Got an abort acknowledgement nobody asked for");
+            }
+            Message::StateAck { hostname, .. } => {
+                debug!("Got a state acknowledgement from {} nobody asked for", hostname);
+            }
+            Message::Hello | Message::WrongRun(_) => {
+                debug!("Got a greeting outside of a handshake");
+            }
+            Message::Status => {
+                debug!("Got asked for our status, which only the agent knows");
+            }
+            Message::StatusReport(_) => {
+                debug!("Got a status report nobody asked for");
+            }
+        }
+        None
+    }
+
+    /// Determine if we know of a segment on host
+    pub fn has_segment(&self, hostname: &str) -> bool {
+        self.current_segments
+            .iter()
+            .any(|segment| segment.hostname == hostname)
+    }
+
+    /// Forget the segment on hostname after it died
+    fn forget_segment(&mut self, hostname: &str) {
+        if let Some(index) = self.current_segments
+            .iter()
+            .position(|s| s.hostname == hostname)
+        {
+            self.current_segments.remove(index);
+            self.cur_num_segments = self.cur_num_segments.saturating_sub(1);
+            self.events.push(Event::PeerDied(String::from(hostname)));
+        }
+    }
+
+    /// Stop the run and pass the abort on to every known segment that has not seen it
+    ///
+    /// The abort is passed on one segment at a time, each getting what is left of wait
+    /// to acknowledge it along with the segments it passed it on to. Segments only count
+    /// as seen once they acknowledged, so those we fail to reach are tried by the others.
+    /// Once wait is used up, the abort is still passed on but no longer waited for.
+    fn abort<T: Transport>(
+        &mut self,
+        transport: &T,
+        mut seen: Vec<String>,
+        wait: Duration,
+    ) -> AbortAck {
+        let mut ack = AbortAck::new(&self.current_hostname);
+        if self.aborted {
+            return ack;
+        }
+        info!("Run {} was aborted - stopping", self.run_id);
+        self.aborted = true;
+        seen.push(self.current_hostname.clone());
+        let deadline = transport.now() + wait.saturating_sub(ABORT_REPLY_MARGIN);
+
+        let targets: Vec<String> = self.current_segments
+            .iter()
+            .map(|segment| segment.hostname.clone())
+            .filter(|hostname| *hostname != self.current_hostname)
+            .collect();
+        for hostname in targets {
+            if seen.contains(&hostname) {
+                continue;
+            }
+            let left = deadline.saturating_duration_since(transport.now());
+            let msg = Message::Abort {
+                run_id: self.run_id.clone(),
+                seen: seen.clone(),
+                wait_ms: left.as_millis() as u64,
+            };
+            if left <= ABORT_REPLY_MARGIN {
+                match self.send_message(transport, &hostname, &msg) {
+                    Ok(()) => ack.unconfirmed.push(hostname),
+                    Err(e) => {
+                        warn!("Unable to pass abort on to {}: {}", hostname, e);
+                        ack.unreachable.push(hostname);
+                    }
+                }
+                continue;
+            }
+            let reply = self.check_allowed(&hostname)
+                .and_then(|()| transport.request_within(&hostname, &msg, left));
+            match reply {
+                Ok(Message::AbortAck(child)) => {
+                    seen.extend(child.hostnames());
+                    ack.children.push(child);
+                }
+                Ok(reply) => {
+                    warn!("Unexpected reply to abort from {}: {:?}", hostname, reply);
+                    ack.unreachable.push(hostname);
+                }
+                Err(ref e) if e.is_timeout() => {
+                    warn!("{} did not acknowledge the abort in time", hostname);
+                    ack.unconfirmed.push(hostname);
+                }
+                Err(e) => {
+                    warn!("Unable to pass abort on to {}: {}", hostname, e);
+                    ack.unreachable.push(hostname);
+                }
+            }
+        }
+        ack
+    }
+
+    /// Send suicide note
+    pub fn send_suicide_note<T: Transport>(&self, transport: &T) -> Result<()> {
+        let msg = Message::SuicideNote(WormSegment::new(TreeState::This, &self.current_hostname));
+        for host in self.current_segments.iter().take(self.gossip_fanout) {
+            if host.relationship == TreeState::This {
+                continue;
+            }
+
+            match self.send_message(transport, &host.hostname, &msg) {
+                Ok(()) => debug!("Sent suicide note to {:?}", host),
+                Err(e) => warn!("Unable to send suicide note to {:?}: {}", host, e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Send the Worm state to a listening worm segment
+    ///
+    /// The new segment is only kept in the tree once the state transfer went through.
+    pub fn send_data_to_host<T: Transport>(&mut self, transport: &T, host: &str) -> Result<()> {
+        self.check_allowed(host)?;
+
+        // The state we send has to include the new segment
+        self.cur_num_segments += 1;
+        self.current_segments
+            .push(WormSegment::new(TreeState::Child, host));
+
+        if let Err(e) = transport.send_state(host, self) {
+            warn!("State transfer to {} failed - forgetting the segment", host);
+            self.current_segments.pop();
+            self.cur_num_segments = self.cur_num_segments.saturating_sub(1);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Upload the program to the wormgate on the specified host
+    pub fn upload_to_host<T: Transport>(&self, transport: &T, host: &str) -> Result<()> {
+        if self.aborted {
+            return Err(PolyError::Aborted(self.run_id.clone()));
+        }
+        self.check_allowed(host)?;
+        let signature = self.binary_signature.as_deref();
+        transport.upload_binary(host, self.wormgate_port_of(host), signature)
+    }
+
+    /// Send the program and Worm state to the specified host
+    pub fn send_to_host<T: Transport>(&mut self, transport: &T, host: &str) -> Result<()> {
+        self.upload_to_host(transport, host)?;
+        self.send_data_to_host(transport, host)
+    }
+
+    /// Hand our observations to the segment on host, which keeps the ones it is missing
+    pub fn push_data<T: Transport>(&self, transport: &T, host: &str) -> Result<()> {
+        self.send_message(transport, host, &Message::PushData(self.observation_data.clone()))
+    }
+
+    /// Allowed hosts which we don't have data from and have no segment, in order
+    ///
+    /// Hosts we failed to infect are left out until their backoff has passed.
+    pub fn candidates(&self, now: Instant) -> Vec<String> {
+        self.hosts_to_ovserve
+            .iter()
+            .filter(|host| {
+                self.is_allowed(host)
+                    && self.failures.may_try(host, now)
+                    && !self.observation_data.contains_key(*host)
+                    && !self.current_segments
+                        .iter()
+                        .any(|h| &h.hostname == *host)
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Send program and Worm state to host and gossip about the new segment
+    pub fn infect<T: Transport>(&mut self, transport: &T, host: &str) -> Result<()> {
+        // Hosts we may not contact are refused before they count as failed infections
+        self.check_allowed(host)?;
+        if let Err(e) = self.send_to_host(transport, host) {
+            let backoff = self.failures.record_failure(host, transport.now());
+            if self.failures.gave_up(host) {
+                warn!("Giving up on {} after repeated failures", host);
+            } else {
+                debug!("Will try {} again in {:?}", host, backoff);
+            }
+            return Err(e);
+        }
+        self.failures.record_success(host);
+
+        // Gossip about it to some other host - with a timeout
+        let msg = Message::NewSegment(WormSegment::new(TreeState::Child, host));
+        for gossip_host in self.current_segments.iter().take(self.gossip_fanout) {
+            if gossip_host.hostname == self.current_hostname {
+                continue;
+            }
+            debug!("Gossip host: {:?}", gossip_host);
+            if let Err(e) = self.send_message(transport, &gossip_host.hostname, &msg) {
+                warn!("Unable to gossip to {:?}: {}", gossip_host, e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Return data to wormgate on current host
+    pub fn return_data<T: Transport>(&self, transport: &T) -> Result<()> {
+        let port = self.wormgate_port_of(&self.current_hostname);
+        transport.post_observation_data(port, &self.observation_data)?;
+
+        for segment in &self.current_segments {
+            if segment.hostname == self.current_hostname {
+                continue;
+            }
+            let msg = Message::GatheringCompleted;
+            if let Err(e) = self.send_message(transport, &segment.hostname, &msg) {
+                warn!("Unable to reach segment {:?}: {}", segment, e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Return whatever data we have to the wormgate on current host, without telling others
+    pub fn return_partial_data<T: Transport>(&self, transport: &T) -> Result<()> {
+        let port = self.wormgate_port_of(&self.current_hostname);
+        transport.post_observation_data(port, &self.observation_data)
+    }
+
+    /// Determine if we have all data we should have before returning it
+    ///
+    /// Hosts we gave up on are not waited for.
+    pub fn is_finished(&self) -> bool {
+        self.hosts_to_ovserve.iter().all(|host| {
+            self.observation_data.contains_key(host.as_str()) || self.failures.gave_up(host)
+        })
+    }
+
+    /// Determine if a known segment has data we are missing
+    pub fn has_missing_data(&self) -> bool {
+        self.current_segments
+            .iter()
+            .any(|segment| !self.observation_data.contains_key(&segment.hostname))
+    }
+
+    /// Query missing data based on known segments
+    pub fn query_missing_data<T: Transport>(&mut self, transport: &T) -> Result<()> {
+        let mut observations = Vec::new();
+        let mut dead = Vec::new();
+
+        // Iterate over known segment
+        for segment in &self.current_segments {
+            debug!("Want to query segment: {:?}", segment);
+            // If we are missing data from any of them - ask for it
+            if !self.observation_data.contains_key(&segment.hostname) {
+                debug!("Querying segment: {:?} for observation", segment);
+                match self.query_segment(transport, &segment.hostname) {
+                    Ok(observation) => observations.push((segment.hostname.clone(), observation)),
+                    Err(ref e) if e.is_connection_refused() => {
+                        warn!("Segment {:?} is gone: {}", segment, e);
+                        dead.push(segment.hostname.clone());
+                    }
+                    Err(e) => warn!("Unable to query {:?}: {}", segment, e),
+                }
+            }
+        }
+
+        for hostname in dead {
+            self.forget_segment(&hostname);
+        }
+        for (hostname, observation) in observations {
+            self.events.push(Event::DataArrived(hostname.clone()));
+            self.observation_data.insert(hostname, observation);
+        }
+        Ok(())
+    }
+
+    /// Determine if the segment on hostname is still there, forgetting it if it is gone
+    pub fn check_segment<T: Transport>(&mut self, transport: &T, hostname: &str) -> bool {
+        match self.query_segment(transport, hostname) {
+            Err(ref e) if e.is_connection_refused() => {
+                warn!("Segment on {} is gone: {}", hostname, e);
+                self.forget_segment(hostname);
+                false
+            }
+            _ => true,
+        }
+    }
+
+    /// Ask the segment on hostname for its observation
+    fn query_segment<T: Transport>(&self, transport: &T, hostname: &str) -> Result<String> {
+        self.check_allowed(hostname)?;
+        let msg = Message::WantData(hostname.to_string());
+        match transport.request(hostname, &msg)? {
+            Message::Observation(Some(observation)) => Ok(observation),
+            Message::Observation(None) => Err(PolyError::Protocol(format!(
+                "{} has no observation for itself",
+                hostname
+            ))),
+            reply => Err(PolyError::Protocol(format!(
+                "Unexpected reply from {}: {:?}",
+                hostname, reply
+            ))),
+        }
+    }
+}
@@ -0,0 +1,474 @@
+
+use serde_json;
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use auth::{self, VerifyingKey};
+use daemon::RUN_DIR_VAR;
+use protocol::{self, DEFAULT_RUN_ID, RUN_ID_VAR};
+use transport::HOSTNAME_VAR;
+use transport::ports::{SegmentPorts, SEGMENT_PORTS_PATH, WORMGATE_PORT_VAR};
+use error::{PolyError, Result};
+use logging::{self, Context, WORMGATE_LOG_PATH};
+
+/// Largest request body we are willing to read, enough for a debug build
+pub const MAX_BODY_LEN: usize = 256 * 1024 * 1024;
+
+/// How long a client may keep us waiting for the next part of its request
+///
+/// Requests are handled one at a time, so an idle client holds up everybody else.
+pub const REQUEST_READ_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A binary uploaded to `/worm_entrance` along with its integrity headers
+#[derive(Debug, Clone, PartialEq)]
+pub struct Upload {
+    pub binary: Vec<u8>,
+    pub run_id: Option<String>, // Run the new segment should join
+    pub sha256: Option<String>,
+    pub signature: Option<String>,
+}
+
+/// Something a fake wormgate received from a segment
+#[derive(Debug, Clone, PartialEq)]
+pub enum Received {
+    /// A segment fetched the observation data
+    ObservationRequest,
+    /// A segment returned the gathered observation data
+    ObservationData(HashMap<String, String>),
+    /// A segment uploaded a binary to spawn a new segment
+    WormEntrance(Upload),
+    /// A segment registered the port it listens on for a run
+    PortRegistration(String, SegmentPorts),
+    /// A segment logging to the wormgate sent a line
+    Log(String),
+}
+
+/// A parsed HTTP request
+struct Request {
+    method: String,
+    path: String,
+    headers: HashMap<String, String>,
+    body: Vec<u8>,
+}
+
+impl Upload {
+    /// Check that the binary matches its digest and is signed as an approved build
+    pub fn verify(&self, verify_key: &VerifyingKey) -> Result<()> {
+        match (self.sha256.as_ref(), self.signature.as_ref()) {
+            (Some(sha256), Some(signature)) => {
+                auth::verify_binary(verify_key, &self.binary, sha256, signature)
+            }
+            (None, _) => Err(PolyError::Auth(String::from("Upload has no digest"))),
+            (_, None) => Err(PolyError::Auth(String::from("Upload has no signature"))),
+        }
+    }
+}
+
+struct WormgateState {
+    hostname: String,
+    observation: String,
+    verify_key: Option<VerifyingKey>,
+    launch_dir: Option<PathBuf>,
+    launched: usize,
+    ports: HashMap<String, SegmentPorts>,
+    received: Vec<Received>,
+}
+
+/// Stand-in for the wormgate running on a single virtual host
+///
+/// Serves `GET /observation_data` with the configured observation, and records
+/// everything posted to `/observation_data` and `/worm_entrance`. With a verify
+/// key, uploads that are not an approved build are refused. Segments register their
+/// ports by posting to `/segment_ports/<run id>`, where peers can get them. Lines
+/// posted to `/log` are logged as they are.
+///
+/// With a launch directory, uploads are also started like a real wormgate does, with
+/// the run, hostname and wormgate port in their environment. Several fake wormgates on
+/// one machine can so drive a run over several virtual hosts.
+pub struct FakeWormgate {
+    listener: TcpListener,
+    state: Arc<Mutex<WormgateState>>,
+}
+
+impl FakeWormgate {
+    /// Bind a wormgate for hostname on the given address, e.g. `127.0.0.1:0`
+    pub fn bind(addr: &str, hostname: &str, observation: &str) -> Result<FakeWormgate> {
+        Ok(FakeWormgate {
+            listener: TcpListener::bind(addr)?,
+            state: Arc::new(Mutex::new(WormgateState {
+                hostname: String::from(hostname),
+                observation: String::from(observation),
+                verify_key: None,
+                launch_dir: None,
+                launched: 0,
+                ports: HashMap::new(),
+                received: Vec::new(),
+            })),
+        })
+    }
+
+    /// Refuse uploads whose signature does not check out with verify_key
+    pub fn require_signed_uploads(&self, verify_key: Option<VerifyingKey>) {
+        self.state.lock().expect("Wormgate state poisoned").verify_key = verify_key;
+    }
+
+    /// Start accepted uploads from dir, which is also the run directory of the segments
+    pub fn launch_uploads(&self, dir: Option<PathBuf>) {
+        self.state.lock().expect("Wormgate state poisoned").launch_dir = dir;
+    }
+
+    /// Port the wormgate is listening on
+    pub fn port(&self) -> Result<u16> {
+        Ok(self.listener.local_addr()?.port())
+    }
+
+    /// Everything received so far, in order
+    pub fn received(&self) -> Vec<Received> {
+        self.state.lock().expect("Wormgate state poisoned").received.clone()
+    }
+
+    /// Binaries uploaded to `/worm_entrance` so far
+    pub fn uploads(&self) -> Vec<Upload> {
+        self.received()
+            .into_iter()
+            .filter_map(|r| match r {
+                Received::WormEntrance(upload) => Some(upload),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Observation data returned to the wormgate, if any
+    pub fn returned_data(&self) -> Option<HashMap<String, String>> {
+        self.received()
+            .into_iter()
+            .rev()
+            .find_map(|r| match r {
+                Received::ObservationData(data) => Some(data),
+                _ => None,
+            })
+    }
+
+    /// Ports registered by the segment of the given run, if any
+    pub fn segment_ports(&self, run_id: &str) -> Option<SegmentPorts> {
+        self.state
+            .lock()
+            .expect("Wormgate state poisoned")
+            .ports
+            .get(run_id)
+            .cloned()
+    }
+
+    /// Handle requests on the current thread forever
+    pub fn serve(&self) -> Result<()> {
+        serve(&self.listener, &self.state)
+    }
+
+    /// Handle requests on a background thread
+    pub fn spawn(&self) -> Result<thread::JoinHandle<Result<()>>> {
+        let listener = self.listener.try_clone()?;
+        let state = self.state.clone();
+        Ok(thread::spawn(move || serve(&listener, &state)))
+    }
+}
+
+fn serve(listener: &TcpListener, state: &Arc<Mutex<WormgateState>>) -> Result<()> {
+    let port = listener.local_addr()?.port();
+    logging::set_context(Context {
+        hostname: state.lock().expect("Wormgate state poisoned").hostname.clone(),
+        role: String::from("wormgate"),
+        ..Context::default()
+    });
+    for conn in listener.incoming() {
+        match conn {
+            Ok(stream) => {
+                let handled = stream
+                    .set_read_timeout(Some(REQUEST_READ_TIMEOUT))
+                    .map_err(PolyError::from)
+                    .and_then(|()| handle_connection(&stream, port, state));
+                if let Err(e) = handled {
+                    warn!("Wormgate unable to handle request: {}", e);
+                }
+            }
+            Err(e) => warn!("Wormgate unable to accept connection: {:?}", e),
+        }
+    }
+    Ok(())
+}
+
+fn handle_connection(
+    stream: &TcpStream,
+    port: u16,
+    state: &Arc<Mutex<WormgateState>>,
+) -> Result<()> {
+    let request = read_request(stream)?;
+    let mut state = state.lock().expect("Wormgate state poisoned");
+    debug!(
+        "Got {} {} ({} bytes)",
+        request.method,
+        request.path,
+        request.body.len()
+    );
+
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/observation_data") => {
+            state.received.push(Received::ObservationRequest);
+            let mut data = HashMap::new();
+            data.insert(
+                format!("{}:{}", state.hostname, port),
+                state.observation.clone(),
+            );
+            write_response(stream, "200 OK", &serde_json::to_vec(&data)?)
+        }
+        ("POST", "/observation_data") => {
+            let data = serde_json::from_slice(&request.body)?;
+            state.received.push(Received::ObservationData(data));
+            write_response(stream, "200 OK", b"")
+        }
+        ("POST", "/worm_entrance") => {
+            let upload = Upload {
+                run_id: request
+                    .headers
+                    .get(&protocol::RUN_ID_HEADER.to_lowercase())
+                    .cloned(),
+                sha256: request.headers.get(&auth::DIGEST_HEADER.to_lowercase()).cloned(),
+                signature: request
+                    .headers
+                    .get(&auth::SIGNATURE_HEADER.to_lowercase())
+                    .cloned(),
+                binary: request.body,
+            };
+            if let Some(ref key) = state.verify_key {
+                match upload.verify(key) {
+                    Ok(()) => debug!("Upload is an approved build"),
+                    Err(e) => {
+                        warn!("Rejecting upload: {}", e);
+                        return write_response(stream, "403 Forbidden", b"");
+                    }
+                }
+            }
+            if let Err(e) = launch(&mut state, port, &upload) {
+                warn!("Unable to launch upload: {}", e);
+                return write_response(stream, "500 Internal Server Error", b"");
+            }
+            state.received.push(Received::WormEntrance(upload));
+            write_response(stream, "200 OK", b"")
+        }
+        ("GET", path) if path.starts_with(SEGMENT_PORTS_PATH) => {
+            let run_id = &path[SEGMENT_PORTS_PATH.len()..];
+            match state.ports.get(run_id) {
+                Some(ports) => write_response(stream, "200 OK", &serde_json::to_vec(ports)?),
+                None => write_response(stream, "404 Not Found", b""),
+            }
+        }
+        ("POST", path) if path.starts_with(SEGMENT_PORTS_PATH) => {
+            let run_id = path[SEGMENT_PORTS_PATH.len()..].to_string();
+            let ports: SegmentPorts = serde_json::from_slice(&request.body)?;
+            state
+                .ports
+                .entry(run_id.clone())
+                .or_default()
+                .update(&ports);
+            state.received.push(Received::PortRegistration(run_id, ports));
+            write_response(stream, "200 OK", b"")
+        }
+        ("POST", WORMGATE_LOG_PATH) => {
+            let line = String::from_utf8_lossy(&request.body).into_owned();
+            // Segments format their lines themselves
+            logging::forward(&line);
+            state.received.push(Received::Log(line));
+            write_response(stream, "200 OK", b"")
+        }
+        _ => write_response(stream, "404 Not Found", b""),
+    }
+}
+
+/// Write the uploaded binary to the launch directory and start it, if we have one
+fn launch(state: &mut WormgateState, port: u16, upload: &Upload) -> Result<()> {
+    let dir = match state.launch_dir {
+        Some(ref dir) => dir.clone(),
+        None => return Ok(()),
+    };
+    let run_id = upload.run_id.as_deref().unwrap_or(DEFAULT_RUN_ID);
+    fs::create_dir_all(&dir)?;
+    state.launched += 1;
+    let path = dir.join(format!("poly-{}-{}", run_id, state.launched));
+    {
+        let mut file = File::create(&path)?;
+        file.write_all(&upload.binary)?;
+        file.set_permissions(fs::Permissions::from_mode(0o755))?;
+    }
+
+    let mut child = Command::new(&path)
+        .env(RUN_ID_VAR, run_id)
+        .env(HOSTNAME_VAR, &state.hostname)
+        .env(WORMGATE_PORT_VAR, port.to_string())
+        .env(RUN_DIR_VAR, &dir)
+        .stdin(Stdio::null())
+        .spawn()?;
+    info!("Launched {:?} for run {} with pid {}", path, run_id, child.id());
+    // Segments daemonize right away, so this only reaps the process we started
+    let _handle = thread::spawn(move || child.wait());
+    Ok(())
+}
+
+/// Read a request with a body delimited by Content-Length
+fn read_request(stream: &TcpStream) -> Result<Request> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    let _n = reader.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let path = parts.next().unwrap_or_default().to_string();
+    if method.is_empty() || path.is_empty() {
+        return Err(PolyError::Protocol(format!("Malformed request line {:?}", line)));
+    }
+
+    let mut headers = HashMap::new();
+    let mut content_length = 0;
+    loop {
+        line.clear();
+        let _n = reader.read_line(&mut line)?;
+        let header = line.trim();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(index) = header.find(':') {
+            let (name, value) = header.split_at(index);
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value[1..].trim().parse().map_err(|_| {
+                    PolyError::Protocol(format!("Invalid Content-Length {:?}", value))
+                })?;
+            } else if name.eq_ignore_ascii_case("transfer-encoding") {
+                return Err(PolyError::Protocol(String::from(
+                    "Only bodies with Content-Length are supported",
+                )));
+            }
+            headers.insert(name.to_lowercase(), value[1..].trim().to_string());
+        }
+    }
+
+    if content_length > MAX_BODY_LEN {
+        return Err(PolyError::Protocol(format!(
+            "Body of {} bytes is too large",
+            content_length
+        )));
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+    Ok(Request {
+        method,
+        path,
+        headers,
+        body,
+    })
+}
+
+fn write_response(mut stream: &TcpStream, status: &str, body: &[u8]) -> Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        body.len()
+    )?;
+    stream.write_all(body)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::time::Instant;
+
+    use auth::SigningKey;
+    use transport::{TcpTransport, Transport};
+    use transport::ports;
+
+    fn wormgate() -> (FakeWormgate, u16) {
+        let wormgate = FakeWormgate::bind("127.0.0.1:0", "127.0.0.1", "observation").unwrap();
+        let _handle = wormgate.spawn().unwrap();
+        let port = wormgate.port().unwrap();
+        (wormgate, port)
+    }
+
+    fn transport() -> TcpTransport {
+        TcpTransport::new("127.0.0.1").with_run_id("run")
+    }
+
+    #[test]
+    fn observation_data() {
+        let (wormgate, port) = wormgate();
+        let data = transport().fetch_observation_data(port).unwrap();
+        assert_eq!(data[&format!("127.0.0.1:{}", port)], "observation");
+
+        let mut returned = HashMap::new();
+        returned.insert(String::from("a"), String::from("observation from a"));
+        transport().post_observation_data(port, &returned).unwrap();
+        assert_eq!(wormgate.returned_data(), Some(returned.clone()));
+        assert_eq!(
+            wormgate.received(),
+            vec![Received::ObservationRequest, Received::ObservationData(returned)]
+        );
+    }
+
+    #[test]
+    fn segment_ports() {
+        let (wormgate, port) = wormgate();
+        ports::register_port(port, "run", false, 4242).unwrap();
+        ports::register_port(port, "run", true, 4241).unwrap();
+        assert_eq!(ports::lookup_port("127.0.0.1", port, "run", false).unwrap(), Some(4242));
+        assert_eq!(ports::lookup_port("127.0.0.1", port, "other", true).unwrap(), None);
+        let registered = wormgate.segment_ports("run").unwrap();
+        assert_eq!(registered.get(true), Some(4241));
+    }
+
+    #[test]
+    fn signed_uploads() {
+        let (wormgate, port) = wormgate();
+        let signing_key = SigningKey::from_bytes(&[7; 32]);
+        wormgate.require_signed_uploads(Some(signing_key.verifying_key()));
+
+        let mut binary = Vec::new();
+        let _n = File::open(env::current_exe().unwrap())
+            .and_then(|mut f| f.read_to_end(&mut binary))
+            .unwrap();
+        let signature = auth::sign_binary(&signing_key, &binary);
+        transport()
+            .upload_binary("127.0.0.1", port, Some(&signature))
+            .unwrap();
+        let uploads = wormgate.uploads();
+        assert_eq!(uploads.len(), 1);
+        assert_eq!(uploads[0].run_id.as_deref(), Some("run"));
+        assert_eq!(uploads[0].signature, Some(signature));
+
+        let other_key = SigningKey::from_bytes(&[8; 32]);
+        let forged = auth::sign_binary(&other_key, &binary);
+        match transport().upload_binary("127.0.0.1", port, Some(&forged)) {
+            Err(PolyError::Http(ref e)) => {
+                assert_eq!(e.status().map(|s| s.as_u16()), Some(403));
+            }
+            result => panic!("Uploaded {:?}", result),
+        }
+        assert_eq!(wormgate.uploads().len(), 1);
+    }
+
+    #[test]
+    fn idle_client_times_out() {
+        let (_wormgate, port) = wormgate();
+        let _idle = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let started = Instant::now();
+        assert!(transport().fetch_observation_data(port).is_ok());
+        assert!(started.elapsed() < REQUEST_READ_TIMEOUT * 3);
+    }
+}